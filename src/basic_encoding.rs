@@ -1,87 +1,253 @@
-//! A simple very inefficient (space and time) encoding.
+//! A simple very space inefficient encoding, useful for debugging. Decoding is a single linear pass.
 
 use super::data_models::leaf_tree::{View, Visitor};
-use super::encoding::{Decoder, Encoder};
-use byteorder::WriteBytesExt;
+use super::encoding::{Decoder, Encoder, Event, IncrementalDecoder};
+use std::cell::Cell;
+use std::io::{self, Write};
 
+#[derive(Clone)]
 pub struct BasicEncoding;
 
 const LIST_MARKER: u8 = 0;
 const LIST_END: u8 = 2;
+/// Value of exactly one byte, which follows
 const VALUE_MARKER: u8 = 1;
+/// Value of any length: u64 length then the bytes
+const BYTES_MARKER: u8 = 3;
+
+fn is_value_marker(marker: u8) -> bool {
+    marker == VALUE_MARKER || marker == BYTES_MARKER
+}
+
+/// Reads the value at the start of data (which must start with a value marker),
+/// returning it and its encoded size, or None if data ends within it.
+fn split_value(data: &[u8]) -> Option<(&[u8], usize)> {
+    if data[0] == VALUE_MARKER {
+        return data.get(1..2).map(|value| (value, 2));
+    }
+    let mut length = [0u8; 8];
+    length.copy_from_slice(data.get(1..9)?);
+    let end = 9usize.checked_add(u64::from_le_bytes(length) as usize)?;
+    data.get(9..end).map(|value| (value, end))
+}
 
 impl Encoder for BasicEncoding {
-    type Value = u8;
-    fn serialize<TView: View<Value = Self::Value>>(&self, v: &TView) -> Vec<u8> {
-        struct Output {
-            vec: Vec<u8>,
+    type Value = Vec<u8>;
+    fn write<TView: View<Value = Self::Value>, W: Write>(
+        &self,
+        v: &TView,
+        out: &mut W,
+    ) -> io::Result<()> {
+        struct Output<'a, W> {
+            out: &'a mut W,
+            /// The first error encountered. Once set, the rest of the tree is skipped.
+            result: io::Result<()>,
+        }
+
+        impl<'a, W: Write> Output<'a, W> {
+            fn write(&mut self, bytes: &[u8]) {
+                if self.result.is_ok() {
+                    self.result = self.out.write_all(bytes);
+                }
+            }
         }
 
-        impl Visitor for Output {
-            type Value = u8;
+        impl<'a, W: Write> Visitor for Output<'a, W> {
+            type Value = Vec<u8>;
             fn visit_list<T: View<Value = Self::Value>>(&mut self, t: &T) {
-                self.vec.write_u8(LIST_MARKER).unwrap();
-                t.visit(self);
-                self.vec.write_u8(LIST_END).unwrap();
+                self.write(&[LIST_MARKER]);
+                if self.result.is_ok() {
+                    t.visit(self);
+                }
+                self.write(&[LIST_END]);
             }
 
             fn visit_value(&mut self, t: Self::Value) {
-                self.vec.write_u8(VALUE_MARKER).unwrap();
-                self.vec.write_u8(t).unwrap();
+                if t.len() == 1 {
+                    self.write(&[VALUE_MARKER, t[0]]);
+                } else {
+                    self.write(&[BYTES_MARKER]);
+                    self.write(&(t.len() as u64).to_le_bytes());
+                    self.write(&t);
+                }
             }
         }
 
-        return v.apply(Output { vec: vec![] }).vec;
+        v.apply(Output {
+            out,
+            result: Ok(()),
+        })
+        .result
     }
 }
 
 impl Decoder for BasicEncoding {
-    type Value = u8;
+    type Value = Vec<u8>;
     fn visit_root<V: Visitor<Value = Self::Value>>(&self, data: &[u8], v: &mut V) {
-        struct Tree<'a> {
-            data: &'a [u8],
+        let root = Tree::new(data, 0, false);
+        root.visit(v);
+        root.length();
+    }
+}
+
+/// The content of a node, starting just after its LIST_MARKER (or at the start of the data for the root).
+///
+/// Decoding is a single pass: each node records its length once visited,
+/// so its parent can continue from the end of it without scanning it again.
+/// Nodes the visitor does not visit are skipped by visiting them with a visitor that ignores everything.
+struct Tree<'a> {
+    data: &'a [u8],
+    /// Offset of data in the whole buffer, for error messages.
+    offset: usize,
+    /// The root is ended by the end of the data, all other nodes by LIST_END.
+    terminated: bool,
+    /// Number of bytes used by this node (including its LIST_END), known once it has been visited.
+    length: Cell<Option<usize>>,
+}
+
+impl<'a> Tree<'a> {
+    fn new(data: &'a [u8], offset: usize, terminated: bool) -> Tree<'a> {
+        Tree {
+            data,
+            offset,
+            terminated,
+            length: Cell::new(None),
+        }
+    }
+
+    fn length(&self) -> usize {
+        if let Some(length) = self.length.get() {
+            return length;
         }
+        self.visit(&mut Skip);
+        self.length.get().unwrap()
+    }
+}
 
-        impl<'a> View for Tree<'a> {
-            type Value = u8;
-            fn visit<V: Visitor<Value = u8>>(&self, v: &mut V) {
-                if self.data.len() == 0 {
-                    return;
+impl<'a> View for Tree<'a> {
+    type Value = Vec<u8>;
+    fn visit<V: Visitor<Value = Vec<u8>>>(&self, v: &mut V) {
+        let data = self.data;
+        let mut i = 0;
+        if data.first().cloned().is_some_and(is_value_marker) {
+            match split_value(data) {
+                Some((value, size)) => {
+                    v.visit_value(value.to_vec());
+                    i = size;
                 }
-                let marker = self.data[0];
-                if marker == VALUE_MARKER {
-                    v.visit_value(self.data[1]);
-                } else {
-                    assert!(marker == LIST_MARKER);
-                    let mut i = 0;
-                    let mut level = 0;
-                    loop {
-                        if self.data.len() == i || self.data[i] == LIST_END {
-                            level = level - 1;
-                            if level == -1 {
-                                return;
-                            }
-                        } else if self.data[i] == LIST_MARKER {
-                            if level == 0 {
-                                let sub_tree = Tree {
-                                    data: &self.data[i + 1..],
-                                };
-                                v.visit_list(&sub_tree);
-                            }
-                            level = level + 1;
-                        } else {
-                            assert_eq!(self.data[i], VALUE_MARKER, "i = {}, level = {}", i, level);
-                            if level == 0 {
-                                panic!();
-                            }
-                            i = i + 1;
-                        }
-                        i = i + 1;
+                None => panic!("Truncated value at {}", self.offset),
+            }
+        } else {
+            loop {
+                match data.get(i) {
+                    Some(&LIST_MARKER) => {
+                        let child = Tree::new(&data[i + 1..], self.offset + i + 1, true);
+                        v.visit_list(&child);
+                        i += 1 + child.length();
                     }
+                    Some(&LIST_END) | None => break,
+                    Some(marker) if is_value_marker(*marker) => panic!(
+                        "Value at {} must be the only content of its node",
+                        self.offset + i
+                    ),
+                    Some(marker) => panic!("Invalid marker {} at {}", marker, self.offset + i),
                 }
             }
         }
 
-        Tree { data }.visit(v);
+        if self.terminated {
+            assert_eq!(
+                data.get(i),
+                Some(&LIST_END),
+                "Expected LIST_END at {}",
+                self.offset + i
+            );
+            i += 1;
+        } else {
+            assert_eq!(i, data.len(), "Unexpected data at {}", self.offset + i);
+        }
+        self.length.set(Some(i));
+    }
+}
+
+/// Visitor that ignores everything, used to find the end of nodes.
+struct Skip;
+
+impl Visitor for Skip {
+    type Value = Vec<u8>;
+    fn visit_list<T: View<Value = Self::Value>>(&mut self, _t: &T) {}
+    fn visit_value(&mut self, _t: Self::Value) {}
+}
+
+/// Progress through a BasicEncoding document, for IncrementalDecoder.
+#[derive(Default)]
+pub struct BasicDecodeState {
+    /// Number of open lists below the root.
+    depth: usize,
+    /// The current node has content, so a value can not be added to it.
+    has_content: bool,
+    /// The current node is a value, so it must end next.
+    after_value: bool,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl IncrementalDecoder for BasicEncoding {
+    type Value = Vec<u8>;
+    type State = BasicDecodeState;
+
+    fn next_event(
+        &self,
+        state: &mut BasicDecodeState,
+        input: &[u8],
+    ) -> io::Result<(Option<Event<Vec<u8>>>, usize)> {
+        let marker = match input.first() {
+            Some(marker) => *marker,
+            None => return Ok((None, 0)),
+        };
+        if state.after_value && marker != LIST_END {
+            return Err(invalid_data(format!(
+                "Expected LIST_END after value, found {}",
+                marker
+            )));
+        }
+        match marker {
+            LIST_MARKER => {
+                state.depth += 1;
+                state.has_content = false;
+                Ok((Some(Event::ListStart), 1))
+            }
+            LIST_END => {
+                if state.depth == 0 {
+                    return Err(invalid_data("Unexpected LIST_END at root".to_string()));
+                }
+                state.depth -= 1;
+                state.has_content = true;
+                state.after_value = false;
+                Ok((Some(Event::ListEnd), 1))
+            }
+            marker if is_value_marker(marker) => {
+                if state.has_content {
+                    return Err(invalid_data(
+                        "Value must be the only content of its node".to_string(),
+                    ));
+                }
+                match split_value(input) {
+                    Some((value, size)) => {
+                        state.has_content = true;
+                        state.after_value = true;
+                        Ok((Some(Event::Value(value.to_vec())), size))
+                    }
+                    None => Ok((None, 0)),
+                }
+            }
+            marker => Err(invalid_data(format!("Invalid marker {}", marker))),
+        }
+    }
+
+    fn can_end(&self, state: &BasicDecodeState) -> bool {
+        state.depth == 0
     }
 }
@@ -0,0 +1,281 @@
+//! Self describing documents: a header saying how the document was written, followed by the encoded tree.
+//!
+//! Header:
+//! - MAGIC (4 bytes), so other files are not mistaken for documents.
+//! - u8: format version (prefix_encoding::FORMAT_VERSION when written).
+//! - u8: encoding id (see EncodingId).
+//! - varint: feature flags (see FLAG_CHECKSUM). Readers reject flags they do not know, so new features can not be silently ignored.
+//!
+//! Then the tree, as written by the encoding (for example PrefixDictionaryEncoding documents start with their dictionary id).
+//! If FLAG_CHECKSUM is set, it is followed by an 8 byte little endian fingerprint of everything after MAGIC
+//! (the rest of the header and the tree), so a corrupted header is detected too.
+//!
+//! open reads the header and decodes the tree with the matching decoder,
+//! so callers do not need to know which encoding was used, and older versions can keep being read when the format changes.
+
+use super::basic_encoding::BasicEncoding;
+use super::data_models::leaf_tree::{View, Visitor};
+use super::encoding::{Decoder, Encoder};
+use super::prefix_encoding::{
+    fingerprint, CompressionOptions, PrefixCompressedEncoding, PrefixDictionaryEncoding,
+    PrefixEncoding, PrefixSizedEncoding, PrefixTunedEncoding, PrefixWindowedDecoding,
+    PrefixWindowedEncoding, TemplateDictionary, FORMAT_VERSION,
+};
+use super::varint::{read_varint, write_varint};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::io::{self, Cursor, Write};
+use std::rc::Rc;
+
+pub const MAGIC: [u8; 4] = *b"exst";
+
+/// The document ends with a fingerprint of its header and tree, which open checks.
+pub const FLAG_CHECKSUM: u64 = 1;
+
+/// Flags this version can read.
+const KNOWN_FLAGS: u64 = FLAG_CHECKSUM;
+
+/// Identifies the encoding of a document. Values are part of the format, so must never be reused.
+///
+/// An id names the decoder rather than the encoder: encoders whose output the same decoder reads share an id,
+/// so the header does not say which of them wrote a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodingId {
+    Basic = 0,
+    Prefix = 1,
+    /// PrefixCompressedEncoding, and everything else PrefixCompressedEncoding decodes:
+    /// PrefixTunedEncoding (whatever its options), SchemaEncoding, and plain data (see prefix_encoding::write_plain_data).
+    PrefixCompressed = 2,
+    PrefixSized = 3,
+    PrefixWindowed = 4,
+    PrefixDictionary = 5,
+}
+
+impl EncodingId {
+    fn from_u8(id: u8) -> Option<EncodingId> {
+        Some(match id {
+            0 => EncodingId::Basic,
+            1 => EncodingId::Prefix,
+            2 => EncodingId::PrefixCompressed,
+            3 => EncodingId::PrefixSized,
+            4 => EncodingId::PrefixWindowed,
+            5 => EncodingId::PrefixDictionary,
+            _ => return None,
+        })
+    }
+}
+
+/// Encoders which can write documents: their output is decoded by the decoder for their id.
+pub trait ContainedEncoding: Encoder<Value = Vec<u8>> {
+    fn encoding_id(&self) -> EncodingId;
+}
+
+impl ContainedEncoding for BasicEncoding {
+    fn encoding_id(&self) -> EncodingId {
+        EncodingId::Basic
+    }
+}
+
+impl ContainedEncoding for PrefixEncoding {
+    fn encoding_id(&self) -> EncodingId {
+        EncodingId::Prefix
+    }
+}
+
+impl ContainedEncoding for PrefixCompressedEncoding {
+    fn encoding_id(&self) -> EncodingId {
+        EncodingId::PrefixCompressed
+    }
+}
+
+impl ContainedEncoding for PrefixTunedEncoding {
+    fn encoding_id(&self) -> EncodingId {
+        EncodingId::PrefixCompressed
+    }
+}
+
+impl ContainedEncoding for PrefixSizedEncoding {
+    fn encoding_id(&self) -> EncodingId {
+        EncodingId::PrefixSized
+    }
+}
+
+impl ContainedEncoding for PrefixWindowedEncoding {
+    fn encoding_id(&self) -> EncodingId {
+        EncodingId::PrefixWindowed
+    }
+}
+
+impl ContainedEncoding for PrefixDictionaryEncoding {
+    fn encoding_id(&self) -> EncodingId {
+        EncodingId::PrefixDictionary
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub encoding: EncodingId,
+    pub flags: u64,
+}
+
+/// Writes a document holding t, encoded with encoder.
+pub fn write_document<E: ContainedEncoding, T: View<Value = Vec<u8>>, W: Write>(
+    encoder: &E,
+    flags: u64,
+    t: &T,
+    out: &mut W,
+) -> io::Result<()> {
+    assert_eq!(flags & !KNOWN_FLAGS, 0, "Unknown flags");
+    out.write_all(&MAGIC)?;
+    let mut header = vec![FORMAT_VERSION, encoder.encoding_id() as u8];
+    write_varint(&mut header, flags)?;
+    if flags & FLAG_CHECKSUM == 0 {
+        out.write_all(&header)?;
+        return encoder.write(t, out);
+    }
+    // The checksum covers the header too, so the document is buffered to compute it.
+    let mut checked = header;
+    encoder.write(t, &mut checked)?;
+    out.write_all(&checked)?;
+    out.write_u64::<LittleEndian>(fingerprint(&checked))
+}
+
+pub fn serialize_document<E: ContainedEncoding, T: View<Value = Vec<u8>>>(
+    encoder: &E,
+    flags: u64,
+    t: &T,
+) -> Vec<u8> {
+    let mut out = vec![];
+    write_document(encoder, flags, t, &mut out).expect("Writing to a Vec should not fail");
+    out
+}
+
+/// A document opened with open: a View of its tree, decoded with the encoding from its header.
+///
+/// The tree is decoded each time it is visited, which panics if it is malformed (see open and Document::try_visit).
+pub struct Document<'a> {
+    pub header: Header,
+    tree: &'a [u8],
+    decoder: DocumentDecoder,
+}
+
+/// The decoder for a document's encoding, with anything open found for it.
+enum DocumentDecoder {
+    Basic,
+    Prefix,
+    PrefixCompressed,
+    PrefixSized,
+    PrefixWindowed,
+    PrefixDictionary(PrefixDictionaryEncoding),
+}
+
+/// Reads the header of a document, checking it can be decoded.
+///
+/// PrefixDictionaryEncoding documents are decoded with whichever of dictionaries they were written with,
+/// and fail with io::ErrorKind::NotFound if it is not there.
+/// Versions, encodings and flags this version does not know fail with io::ErrorKind::Unsupported,
+/// and anything else which is not a valid header (or a checksum mismatch) with io::ErrorKind::InvalidData.
+///
+/// Only the header and checksum are checked: the tree itself is not decoded until the Document is visited,
+/// which panics if it is corrupt (Document::try_visit fails instead, for the encodings it can check).
+/// Documents which may be corrupted should be written with FLAG_CHECKSUM, so corruption is detected here instead.
+pub fn open<'a>(
+    data: &'a [u8],
+    dictionaries: &[Rc<TemplateDictionary>],
+) -> io::Result<Document<'a>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+    let unsupported = |message: &str| io::Error::new(io::ErrorKind::Unsupported, message);
+
+    if data.len() < MAGIC.len() + 2 || data[..MAGIC.len()] != MAGIC {
+        return Err(invalid("Not a document"));
+    }
+    // Every version so far is read the same way: later versions which change the format would be handled here.
+    let version = data[MAGIC.len()];
+    if version != FORMAT_VERSION {
+        return Err(unsupported("Unsupported format version"));
+    }
+    let encoding = EncodingId::from_u8(data[MAGIC.len() + 1])
+        .ok_or_else(|| unsupported("Unsupported encoding"))?;
+    let mut rdr = Cursor::new(&data[MAGIC.len() + 2..]);
+    let flags = read_varint(&mut rdr)?;
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(unsupported("Unsupported feature flags"));
+    }
+
+    let header_size = MAGIC.len() + 2 + rdr.position() as usize;
+    let mut tree = &data[header_size..];
+    if flags & FLAG_CHECKSUM != 0 {
+        if tree.len() < 8 {
+            return Err(invalid("Missing checksum"));
+        }
+        let (checked, checksum) = data[MAGIC.len()..].split_at(data.len() - MAGIC.len() - 8);
+        if LittleEndian::read_u64(checksum) != fingerprint(checked) {
+            return Err(invalid("Checksum mismatch"));
+        }
+        tree = &tree[..tree.len() - 8];
+    }
+
+    let decoder = match encoding {
+        EncodingId::Basic => DocumentDecoder::Basic,
+        EncodingId::Prefix => DocumentDecoder::Prefix,
+        EncodingId::PrefixCompressed => DocumentDecoder::PrefixCompressed,
+        EncodingId::PrefixSized => DocumentDecoder::PrefixSized,
+        EncodingId::PrefixWindowed => DocumentDecoder::PrefixWindowed,
+        EncodingId::PrefixDictionary => {
+            let dictionary = dictionaries
+                .iter()
+                .find(|dictionary| dictionary.is_used_by(tree))
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Unknown dictionary"))?;
+            DocumentDecoder::PrefixDictionary(PrefixDictionaryEncoding {
+                dictionary,
+                // Only used when encoding
+                options: CompressionOptions::default(),
+            })
+        }
+    };
+
+    Ok(Document {
+        header: Header {
+            version,
+            encoding,
+            flags,
+        },
+        tree,
+        decoder,
+    })
+}
+
+impl<'a> Document<'a> {
+    /// Visits the tree like View::visit, but fails if it is malformed or truncated, and nothing is visited.
+    ///
+    /// Only documents decoded by PrefixCompressedEncoding, PrefixWindowedDecoding and PrefixDictionaryEncoding are checked:
+    /// others are visited with View::visit, so still panic if they are corrupt.
+    pub fn try_visit<V: Visitor<Value = Vec<u8>>>(&self, v: &mut V) -> io::Result<()> {
+        match &self.decoder {
+            DocumentDecoder::PrefixCompressed => {
+                PrefixCompressedEncoding.try_visit_root(self.tree, v)
+            }
+            DocumentDecoder::PrefixWindowed => PrefixWindowedDecoding.try_visit_root(self.tree, v),
+            DocumentDecoder::PrefixDictionary(decoder) => decoder.try_visit_root(self.tree, v),
+            DocumentDecoder::Basic | DocumentDecoder::Prefix | DocumentDecoder::PrefixSized => {
+                self.visit(v);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'a> View for Document<'a> {
+    type Value = Vec<u8>;
+    fn visit<V: Visitor<Value = Vec<u8>>>(&self, v: &mut V) {
+        match &self.decoder {
+            DocumentDecoder::Basic => BasicEncoding.visit_root(self.tree, v),
+            DocumentDecoder::Prefix => PrefixEncoding.visit_root(self.tree, v),
+            DocumentDecoder::PrefixCompressed => PrefixCompressedEncoding.visit_root(self.tree, v),
+            DocumentDecoder::PrefixSized => PrefixSizedEncoding.visit_root(self.tree, v),
+            DocumentDecoder::PrefixWindowed => PrefixWindowedDecoding.visit_root(self.tree, v),
+            DocumentDecoder::PrefixDictionary(decoder) => decoder.visit_root(self.tree, v),
+        }
+    }
+}
@@ -31,7 +31,7 @@ pub mod concrete {
         Value: Clone,
     {
         type Value = Value;
-        fn visit<V: Visitor<Value = Self::Value>>(&self, v: &mut V) {
+        fn visit<V: Visitor<Value = Value>>(&self, v: &mut V) {
             match self {
                 Concrete::List(list) => {
                     for c in list {
@@ -75,6 +75,6 @@ pub mod concrete {
             }
         }
 
-        return t.apply(Out(Concrete::List(vec![]))).0;
+        t.apply(Out(Concrete::List(vec![]))).0
     }
 }
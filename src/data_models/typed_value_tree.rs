@@ -41,8 +41,8 @@ pub mod concrete {
     where
         N: Eq + Hash,
     {
-        type_name: N,
-        content: StructOrValue<N>,
+        pub type_name: N,
+        pub content: StructOrValue<N>,
     }
 
     /// Content helper for Concrete.
@@ -79,7 +79,7 @@ pub mod concrete {
         type N = N;
         fn visit<V: MapVisitor<N = Self::N>>(&self, v: &mut V) {
             for (k, children) in self.iter() {
-                v.visit(&k, children);
+                v.visit(k, children);
             }
         }
     }
@@ -150,7 +150,7 @@ pub mod concrete {
             }
         }
 
-        return t.apply(copier(None)).t.unwrap();
+        t.apply(copier(None)).t.unwrap()
     }
 
     #[cfg(test)]
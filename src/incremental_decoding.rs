@@ -0,0 +1,359 @@
+//! Drivers for IncrementalDecoders: decode leaf trees from bytes as they arrive instead of from a complete buffer.
+//!
+//! Memory use is bounded by the depth of the tree (the decoder's State) plus one chunk of input,
+//! so arbitrarily large documents can be processed from files or network streams.
+//!
+//! Input is read with Read, which BufRead sources also implement: EventReader reads in chunks itself,
+//! so sources do not need to be wrapped in a BufReader.
+//!
+//! Only encodings implementing IncrementalDecoder (BasicEncoding and PrefixEncoding) can be decoded this way.
+//! PrefixCompressedEncoding and its variants are not: their templates can be referenced from anywhere later in the document,
+//! so memory would not be bounded by the depth of the tree.
+
+use super::data_models::leaf_tree::{View, Visitor};
+use super::encoding::{Event, IncrementalDecoder};
+use std::cell::{Cell, RefCell};
+use std::io::{self, Read};
+
+/// Push based incremental decoding.
+///
+/// Feed input with push as it becomes available, and pull events with next_event.
+/// When next_event returns None, more input is needed: decoding resumes where it left off after the next push.
+pub struct IncrementalDecode<D: IncrementalDecoder> {
+    decoder: D,
+    state: D::State,
+    /// Input not yet consumed by the decoder starts at buffer[start].
+    buffer: Vec<u8>,
+    start: usize,
+}
+
+impl<D: IncrementalDecoder> IncrementalDecode<D> {
+    pub fn new(decoder: D) -> IncrementalDecode<D> {
+        IncrementalDecode {
+            decoder,
+            state: D::State::default(),
+            buffer: vec![],
+            start: 0,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        // Consumed input is only dropped once it is most of the buffer,
+        // so the unconsumed tail is not moved on every push (each byte is moved a bounded number of times).
+        if self.start > self.buffer.len() / 2 {
+            self.buffer.drain(..self.start);
+            self.start = 0;
+        }
+        self.buffer.extend_from_slice(data);
+    }
+
+    pub fn next_event(&mut self) -> io::Result<Option<Event<D::Value>>> {
+        loop {
+            let (event, consumed) = self
+                .decoder
+                .next_event(&mut self.state, &self.buffer[self.start..])?;
+            self.start += consumed;
+            if event.is_some() || consumed == 0 {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// True if the input pushed so far is a complete tree, so the input may end here.
+    pub fn can_end(&self) -> bool {
+        self.start == self.buffer.len() && self.decoder.can_end(&self.state)
+    }
+}
+
+const CHUNK_SIZE: usize = 4096;
+
+/// Pull based incremental decoding from a Read.
+///
+/// Errors from the reader (including io::ErrorKind::WouldBlock from non-blocking sources) are returned from next_event,
+/// which can be called again to resume once more input is available.
+pub struct EventReader<D: IncrementalDecoder, R: Read> {
+    decode: IncrementalDecode<D>,
+    reader: R,
+    /// Input is read into this, then pushed to decode.
+    chunk: Vec<u8>,
+}
+
+impl<D: IncrementalDecoder, R: Read> EventReader<D, R> {
+    pub fn new(decoder: D, reader: R) -> EventReader<D, R> {
+        EventReader {
+            decode: IncrementalDecode::new(decoder),
+            reader,
+            chunk: vec![0; CHUNK_SIZE],
+        }
+    }
+
+    /// Returns the next event, or None at the end of a complete tree.
+    pub fn next_event(&mut self) -> io::Result<Option<Event<D::Value>>> {
+        loop {
+            if let Some(event) = self.decode.next_event()? {
+                return Ok(Some(event));
+            }
+            let count = match self.reader.read(&mut self.chunk) {
+                Ok(count) => count,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if count == 0 {
+                if self.decode.can_end() {
+                    return Ok(None);
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Input ended inside a tree",
+                ));
+            }
+            self.decode.push(&self.chunk[..count]);
+        }
+    }
+}
+
+/// Decodes a tree from reader, calling the visitor as the input is read.
+///
+/// Unlike Decoder::visit_root, nodes passed to visit_list can only be visited once (and only during the visit_list call),
+/// since their content is read from the stream as they are visited. Nodes that are not visited are skipped.
+pub fn visit_read<D, R, V>(decoder: D, reader: R, v: &mut V) -> io::Result<()>
+where
+    D: IncrementalDecoder,
+    R: Read,
+    V: Visitor<Value = D::Value>,
+{
+    let source = Source {
+        reader: RefCell::new(EventReader::new(decoder, reader)),
+        error: RefCell::new(None),
+    };
+    let root = Node {
+        source: &source,
+        depth: 0,
+        visited: Cell::new(false),
+    };
+    root.visit(v);
+    match source.error.into_inner() {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+struct Source<D: IncrementalDecoder, R: Read> {
+    reader: RefCell<EventReader<D, R>>,
+    /// The first error encountered. Once set, no more events are produced.
+    error: RefCell<Option<io::Error>>,
+}
+
+impl<D: IncrementalDecoder, R: Read> Source<D, R> {
+    /// Returns None at the end of the tree or after an error.
+    fn next_event(&self) -> Option<Event<D::Value>> {
+        if self.error.borrow().is_some() {
+            return None;
+        }
+        match self.reader.borrow_mut().next_event() {
+            Ok(event) => event,
+            Err(error) => {
+                *self.error.borrow_mut() = Some(error);
+                None
+            }
+        }
+    }
+}
+
+struct Node<'a, D: IncrementalDecoder, R: Read> {
+    source: &'a Source<D, R>,
+    depth: usize,
+    visited: Cell<bool>,
+}
+
+impl<'a, D: IncrementalDecoder, R: Read> View for Node<'a, D, R> {
+    type Value = D::Value;
+    fn visit<V: Visitor<Value = Self::Value>>(&self, v: &mut V) {
+        assert!(
+            !self.visited.replace(true),
+            "Streamed nodes can only be visited once"
+        );
+        loop {
+            match self.source.next_event() {
+                Some(Event::ListStart) => {
+                    let child = Node {
+                        source: self.source,
+                        depth: self.depth + 1,
+                        visited: Cell::new(false),
+                    };
+                    v.visit_list(&child);
+                    if !child.visited.get() {
+                        child.visit(&mut Skip::new());
+                    }
+                }
+                Some(Event::ListEnd) => {
+                    assert!(self.depth > 0, "Decoder produced ListEnd for the root");
+                    return;
+                }
+                Some(Event::Value(value)) => v.visit_value(value),
+                None => return,
+            }
+        }
+    }
+}
+
+/// Visitor that ignores everything.
+struct Skip<Value>(std::marker::PhantomData<Value>);
+
+impl<Value> Skip<Value> {
+    fn new() -> Skip<Value> {
+        Skip(std::marker::PhantomData)
+    }
+}
+
+impl<Value> Visitor for Skip<Value> {
+    type Value = Value;
+    fn visit_list<T: View<Value = Self::Value>>(&mut self, _t: &T) {}
+    fn visit_value(&mut self, _t: Self::Value) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::basic_encoding::BasicEncoding;
+    use super::super::data_models::leaf_tree::concrete::{view_to_concrete, Concrete};
+    use super::super::data_models::leaf_tree::{View, Visitor};
+    use super::super::encoding::{Encoder, Event, IncrementalDecoder};
+    use super::super::prefix_encoding::PrefixEncoding;
+    use super::*;
+
+    fn tree() -> Concrete<Vec<u8>> {
+        let mut items = vec![Concrete::List(vec![])];
+        for i in 0..3000 {
+            items.push(Concrete::List(vec![
+                Concrete::Value(vec![i as u8]),
+                Concrete::List(vec![Concrete::Value(vec![2])]),
+            ]));
+        }
+        items.push(Concrete::List(vec![Concrete::Value(vec![1])]));
+        Concrete::List(items)
+    }
+
+    /// Collects the visited tree, which requires the streamed nodes be visited during visit_list.
+    struct Collect(Concrete<Vec<u8>>);
+
+    impl Visitor for Collect {
+        type Value = Vec<u8>;
+        fn visit_list<T: View<Value = Vec<u8>>>(&mut self, t: &T) {
+            let child = t.apply(Collect(Concrete::List(vec![]))).0;
+            match &mut self.0 {
+                Concrete::List(list) => list.push(child),
+                Concrete::Value(_) => panic!(),
+            }
+        }
+        fn visit_value(&mut self, value: Vec<u8>) {
+            self.0 = Concrete::Value(value);
+        }
+    }
+
+    fn check_read<D: Encoder<Value = Vec<u8>> + IncrementalDecoder<Value = Vec<u8>> + Clone>(
+        c: &Concrete<Vec<u8>>,
+        e: D,
+    ) {
+        let encoded = e.serialize(c);
+        let mut out = Collect(Concrete::List(vec![]));
+        visit_read(e.clone(), encoded.as_slice(), &mut out).unwrap();
+        assert_eq!(&out.0, c);
+
+        // Truncated input is an error
+        let mut out = Collect(Concrete::List(vec![]));
+        let error = visit_read(e, &encoded[..encoded.len() - 1], &mut out).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    /// Pushes one byte at a time, checking decoding resumes correctly.
+    fn check_push<D: Encoder<Value = Vec<u8>> + IncrementalDecoder<Value = Vec<u8>> + Clone>(
+        c: &Concrete<Vec<u8>>,
+        e: D,
+    ) {
+        let encoded = e.serialize(c);
+        let mut expected = vec![];
+        let mut reader = EventReader::new(e.clone(), encoded.as_slice());
+        while let Some(event) = reader.next_event().unwrap() {
+            expected.push(event);
+        }
+
+        let mut events = vec![];
+        let mut decode = IncrementalDecode::new(e);
+        for b in &encoded {
+            decode.push(&[*b]);
+            while let Some(event) = decode.next_event().unwrap() {
+                events.push(event);
+            }
+        }
+        assert!(decode.can_end());
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn read_basic() {
+        check_read(&tree(), BasicEncoding);
+        check_push(&tree(), BasicEncoding);
+        check_read(&Concrete::Value(vec![5]), BasicEncoding);
+    }
+
+    #[test]
+    fn read_prefix() {
+        check_read(&tree(), PrefixEncoding);
+        check_push(&tree(), PrefixEncoding);
+        check_read(&Concrete::Value(vec![5]), PrefixEncoding);
+        check_read(&Concrete::List(vec![]), PrefixEncoding);
+    }
+
+    #[test]
+    fn read_skips_unvisited() {
+        struct FirstOnly {
+            count: usize,
+            first: Option<Concrete<Vec<u8>>>,
+        }
+        impl Visitor for FirstOnly {
+            type Value = Vec<u8>;
+            fn visit_list<T: View<Value = Vec<u8>>>(&mut self, t: &T) {
+                self.count += 1;
+                if self.first.is_none() {
+                    self.first = Some(view_to_concrete(t));
+                }
+            }
+            fn visit_value(&mut self, _: Vec<u8>) {}
+        }
+
+        fn check<D: Encoder<Value = Vec<u8>> + IncrementalDecoder<Value = Vec<u8>> + Clone>(e: D) {
+            let encoded = e.serialize(&tree());
+            let mut out = FirstOnly {
+                count: 0,
+                first: None,
+            };
+            visit_read(e, encoded.as_slice(), &mut out).unwrap();
+            assert_eq!(out.count, 3002);
+            assert_eq!(out.first, Some(Concrete::List(vec![])));
+        }
+
+        check(BasicEncoding);
+        check(PrefixEncoding);
+    }
+
+    #[test]
+    fn read_events() {
+        let c = Concrete::List(vec![Concrete::Value(vec![3]), Concrete::List(vec![])]);
+        let encoded = PrefixEncoding.serialize(&c);
+        let mut reader = EventReader::new(PrefixEncoding, encoded.as_slice());
+        let mut events = vec![];
+        while let Some(event) = reader.next_event().unwrap() {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            vec![
+                Event::ListStart,
+                Event::Value(vec![3]),
+                Event::ListEnd,
+                Event::ListStart,
+                Event::ListEnd
+            ]
+        );
+    }
+}
@@ -105,3 +105,48 @@ where
         }
     }
 }
+
+/// Type level description of the TypeView of every value of a type, which templates can be derived from
+/// (see type_to_leaf::schema_tree_template).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schema {
+    /// A Terminal, with the number of bytes in all of its values if that is fixed.
+    Terminal { id: u128, size: Option<u32> },
+    /// A Struct, with its fields in the order it visits them.
+    Struct { id: u128, fields: Vec<FieldSchema> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldSchema {
+    /// A field visited with visit_single_field.
+    Single { name: u128, schema: Schema },
+    /// A field visited with visit_list_field. Its length varies, so templates leave it to the tree stream.
+    List { name: u128 },
+}
+
+/// Implement this for Terminal and Struct types to allow deriving templates from the type instead of searching values for redundancy.
+/// Must describe exactly what the type's TypeView visits.
+pub trait HasSchema {
+    fn schema() -> Schema;
+}
+
+pub fn single_field_schema<T: HasSchema>(name: u128) -> FieldSchema {
+    FieldSchema::Single {
+        name,
+        schema: T::schema(),
+    }
+}
+
+/// Implement this for types whose in memory representation is the data stream of their schema's byte pattern
+/// (see type_to_leaf::schema_byte_pattern), so slices of them can be encoded by copying memory, and decoded in place
+/// (see prefix_encoding::write_plain_data and prefix_encoding::read_plain_data).
+///
+/// # Safety
+/// Implementors must be #[repr(C)] with no padding, and valid for every bit pattern.
+/// Each Terminal in the schema must have a fixed size, and bytes equal to its in memory representation on little endian platforms
+/// (for example little endian integers). write_plain_data and read_plain_data fail on big endian platforms,
+/// where the in memory representation differs.
+pub unsafe trait PlainData: HasSchema + Copy + 'static {
+    /// The offset in memory of each Terminal, in the order the schema visits them (see std::mem::offset_of).
+    fn terminal_offsets() -> Vec<usize>;
+}
@@ -1,106 +1,513 @@
 //! Functionality for creating and using (encode and decode) templates for leaf trees. These templates enable deduplicating similar aspects of leaf trees, specifically redundant structural (shape) information and identical values.
 
-pub struct BytePatternTemplate<'a> {
-    size: u32,
-    content: BytePatternChild<'a>,
+use std::rc::Rc;
+
+/// Generates a tree from a fixed size run of bytes in the data stream.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BytePatternTemplate {
+    /// Number of bytes consumed from the stream by each use (the stride).
+    pub size: u32,
+    pub content: BytePatternChild,
 }
 
-pub enum BytePatternChild<'a> {
-    List(Vec<BytePatternChild<'a>>),
-    ConstantValue(u8),
-    ValueFromStreamAtOffset(u32),
-    TemplateUse(OffsetTemplateUse<&'a BytePatternTemplate<'a>>),
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BytePatternChild {
+    List(Vec<BytePatternChild>),
+    ConstantValue(Vec<u8>),
+    ValueFromStreamAtOffset { offset: u32, length: u32 },
+    TemplateUse(OffsetTemplateUse<Rc<BytePatternTemplate>>),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct OffsetTemplateUse<T> {
-    template: T,
-    offset: u32,
+    pub template: T,
+    /// Start of the template's bytes, relative to the start of the enclosing template's bytes.
+    pub offset: u32,
 }
 
-pub enum TreeTemplate<'a> {
-    List(Vec<TreeTemplate<'a>>),
-    ConstantValue(u8),
+/// Generates a tree by filling holes (ValueFromStream and TreeFromStream) with trees from the stream, in pre-order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TreeTemplate {
+    List(Vec<TreeTemplate>),
+    ConstantValue(Vec<u8>),
     ValueFromStream,
     TreeFromStream,
-    TreeTemplateUse(&'a TreeTemplate<'a>),
-    BytePatternTemplateUse(&'a BytePatternTemplate<'a>),
+    TreeTemplateUse(Rc<TreeTemplate>),
+    BytePatternTemplateUse(Rc<BytePatternTemplate>),
 }
 
 use super::data_models::leaf_tree::concrete::{view_to_concrete, Concrete};
-use super::data_models::leaf_tree::View;
+use super::data_models::leaf_tree::{View, Visitor};
+use super::encoding::Encoder;
+use super::prefix_encoding::PrefixEncoding;
+use std::collections::HashSet;
+use std::fmt;
 
-pub fn assert_compliance<TView: View<Value = u8>>(view: &TView, template: &TreeTemplate) {
-    let c = view_to_concrete(view);
-    assert_compliance_concreate(&c, template);
+/// View of the tree a use of a BytePatternTemplate generates from its data stream.
+/// The tree is expanded as it is visited: values are read from the data stream when visited.
+pub struct BytePatternView<'a> {
+    pattern: &'a BytePatternChild,
+    data: &'a [u8],
 }
 
-fn assert_compliance_concreate(c: &Concrete<u8>, template: &TreeTemplate) {
-    match template {
-        TreeTemplate::List(children) => {
-            match c {
-                Concrete::List(list) => {
-                    assert_eq!(list.len(), children.len());
-                    for i in 0..list.len() {
-                        assert_compliance_concreate(&list[i], &children[i]);
-                    }
+impl<'a> BytePatternView<'a> {
+    /// data is the use's data stream, so must have the template's size.
+    pub fn new(template: &'a BytePatternTemplate, data: &'a [u8]) -> BytePatternView<'a> {
+        assert_eq!(
+            data.len(),
+            template.size as usize,
+            "Data stream must have the template's size"
+        );
+        BytePatternView {
+            pattern: &template.content,
+            data,
+        }
+    }
+}
+
+impl<'a> View for BytePatternView<'a> {
+    type Value = Vec<u8>;
+    fn visit<V: Visitor<Value = Vec<u8>>>(&self, v: &mut V) {
+        match self.pattern {
+            BytePatternChild::List(children) => {
+                for child in children {
+                    v.visit_list(&BytePatternView {
+                        pattern: child,
+                        data: self.data,
+                    });
                 }
-                Concrete::Value(_) => panic!(),
-            };
+            }
+            BytePatternChild::ConstantValue(value) => v.visit_value(value.clone()),
+            BytePatternChild::ValueFromStreamAtOffset { offset, length } => {
+                let offset = *offset as usize;
+                v.visit_value(self.data[offset..offset + *length as usize].to_vec());
+            }
+            BytePatternChild::TemplateUse(template_use) => {
+                let offset = template_use.offset as usize;
+                BytePatternView {
+                    pattern: &template_use.template.content,
+                    data: &self.data[offset..offset + template_use.template.size as usize],
+                }
+                .visit(v)
+            }
         }
-        TreeTemplate::ConstantValue(value) => {
-            match c {
-                Concrete::List(_) => panic!(),
-                Concrete::Value(tree_value) => assert_eq!(value, tree_value),
+    }
+}
+
+/// The data stream for a use of template reproducing view,
+/// or every way view does not comply with template (see check_pattern_compliance).
+pub fn extract_byte_pattern_data<TView: View<Value = Vec<u8>>>(
+    view: &TView,
+    template: &BytePatternTemplate,
+) -> Result<Vec<u8>, Vec<Mismatch>> {
+    check_pattern_compliance(view, template)?;
+    Ok(byte_pattern_data(view, template))
+}
+
+/// The tree stream for a use of template reproducing view, as prefix_encoding::TreeTemplateView reads it,
+/// or every way view does not comply with template (see check_compliance).
+pub fn extract_tree_stream<TView: View<Value = Vec<u8>>>(
+    view: &TView,
+    template: &TreeTemplate,
+) -> Result<Vec<u8>, Vec<Mismatch>> {
+    check_compliance(view, template)?;
+    let mut out = vec![];
+    write_tree_stream(view, template, &mut out);
+    Ok(out)
+}
+
+/// The data stream for a use of template reproducing view, which must comply with template.
+/// This is not checked, beyond panicking where view does not fit the template's structure.
+pub fn byte_pattern_data<TView: View<Value = Vec<u8>>>(
+    view: &TView,
+    template: &BytePatternTemplate,
+) -> Vec<u8> {
+    let mut data = vec![0; template.size as usize];
+    fill_byte_pattern_data(view, &template.content, &mut data);
+    data
+}
+
+/// Writes the values of view which come from the stream into data.
+fn fill_byte_pattern_data<TView: View<Value = Vec<u8>>>(
+    view: &TView,
+    pattern: &BytePatternChild,
+    data: &mut [u8],
+) {
+    match pattern {
+        BytePatternChild::List(children) => {
+            let mut filler = PatternFiller {
+                children: children.iter(),
+                data,
             };
+            view.visit(&mut filler);
+            assert!(
+                filler.children.next().is_none(),
+                "Tree has fewer children than its template"
+            );
+        }
+        BytePatternChild::ConstantValue(_) => {}
+        BytePatternChild::ValueFromStreamAtOffset { offset, length } => {
+            let value = view
+                .apply(ValueTaker(None))
+                .0
+                .expect("Template expected a value");
+            assert_eq!(value.len(), *length as usize, "Value has the wrong length");
+            let offset = *offset as usize;
+            data[offset..offset + value.len()].copy_from_slice(&value);
         }
-        TreeTemplate::ValueFromStream => {
-            match c {
-                Concrete::List(_) => panic!(),
-                Concrete::Value(_) => {}
+        BytePatternChild::TemplateUse(template_use) => {
+            let offset = template_use.offset as usize;
+            let size = template_use.template.size as usize;
+            fill_byte_pattern_data(
+                view,
+                &template_use.template.content,
+                &mut data[offset..offset + size],
+            )
+        }
+    }
+}
+
+/// Takes the value of the node it visits, panicking if it is a list.
+struct ValueTaker(Option<Vec<u8>>);
+
+impl Visitor for ValueTaker {
+    type Value = Vec<u8>;
+    fn visit_list<T: View<Value = Self::Value>>(&mut self, _t: &T) {
+        panic!("Template expected a value");
+    }
+    fn visit_value(&mut self, t: Self::Value) {
+        self.0 = Some(t);
+    }
+}
+
+struct PatternFiller<'a, 'b> {
+    children: std::slice::Iter<'a, BytePatternChild>,
+    data: &'b mut [u8],
+}
+
+impl<'a, 'b> Visitor for PatternFiller<'a, 'b> {
+    type Value = Vec<u8>;
+    fn visit_list<T: View<Value = Self::Value>>(&mut self, t: &T) {
+        let child = self
+            .children
+            .next()
+            .expect("Tree has more children than its template");
+        fill_byte_pattern_data(t, child, self.data);
+    }
+    fn visit_value(&mut self, _t: Self::Value) {
+        panic!("Template expected a list");
+    }
+}
+
+/// Writes the tree stream for a use of template reproducing view, which must comply with template.
+/// Holes are written in PrefixEncoding.
+fn write_tree_stream<TView: View<Value = Vec<u8>>>(
+    view: &TView,
+    template: &TreeTemplate,
+    out: &mut Vec<u8>,
+) {
+    match template {
+        TreeTemplate::List(children) => {
+            let mut writer = TreeStreamWriter {
+                children: children.iter(),
+                out,
             };
+            view.visit(&mut writer);
+            assert!(
+                writer.children.next().is_none(),
+                "Tree has fewer children than its template"
+            );
+        }
+        TreeTemplate::ConstantValue(_) => {}
+        TreeTemplate::ValueFromStream | TreeTemplate::TreeFromStream => {
+            PrefixEncoding.write(view, out).unwrap()
+        }
+        TreeTemplate::TreeTemplateUse(template) => write_tree_stream(view, template, out),
+        TreeTemplate::BytePatternTemplateUse(pattern) => {
+            out.extend_from_slice(&byte_pattern_data(view, pattern))
         }
-        TreeTemplate::TreeFromStream => {}
-        TreeTemplate::TreeTemplateUse(template) => assert_compliance_concreate(c, template),
-        TreeTemplate::BytePatternTemplateUse(_) => panic!("Not Implemented"),
     }
 }
 
-/// Assumes template does not contain overlaps (multiple places in the tree sourced from the same location in the template)
-pub fn assert_pattern_compliance<TView: View<Value = u8>>(
+struct TreeStreamWriter<'a, 'b> {
+    children: std::slice::Iter<'a, TreeTemplate>,
+    out: &'b mut Vec<u8>,
+}
+
+impl<'a, 'b> Visitor for TreeStreamWriter<'a, 'b> {
+    type Value = Vec<u8>;
+    fn visit_list<T: View<Value = Self::Value>>(&mut self, t: &T) {
+        let child = self
+            .children
+            .next()
+            .expect("Tree has more children than its template");
+        write_tree_stream(t, child, self.out);
+    }
+    fn visit_value(&mut self, _t: Self::Value) {
+        panic!("Template expected a list");
+    }
+}
+
+/// A way a tree does not comply with a template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Indexes of the children leading from the root to the node that does not comply.
+    pub path: Vec<usize>,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "at {:?}: expected {}, found {}",
+            self.path, self.expected, self.actual
+        )
+    }
+}
+
+/// Checks that view is a tree template can generate, returning every mismatch.
+/// Byte pattern templates used by template are also checked for values which are out of range or overlap.
+pub fn check_compliance<TView: View<Value = Vec<u8>>>(
+    view: &TView,
+    template: &TreeTemplate,
+) -> Result<(), Vec<Mismatch>> {
+    let mut checker = Checker::default();
+    checker.check_tree(&view_to_concrete(view), template);
+    checker.result()
+}
+
+/// Checks that view is a tree template can generate, returning every mismatch.
+/// template is also checked for values which are out of range or overlap
+/// (multiple places in the tree sourced from the same bytes in the stream).
+pub fn check_pattern_compliance<TView: View<Value = Vec<u8>>>(
+    view: &TView,
+    template: &BytePatternTemplate,
+) -> Result<(), Vec<Mismatch>> {
+    let mut checker = Checker::default();
+    checker.check_pattern_template(&view_to_concrete(view), template);
+    checker.result()
+}
+
+pub fn assert_compliance<TView: View<Value = Vec<u8>>>(view: &TView, template: &TreeTemplate) {
+    if let Err(mismatches) = check_compliance(view, template) {
+        panic!(
+            "Tree does not comply with template {}",
+            describe_all(&mismatches)
+        );
+    }
+}
+
+pub fn assert_pattern_compliance<TView: View<Value = Vec<u8>>>(
     view: &TView,
     template: &BytePatternTemplate,
 ) {
-    let c = view_to_concrete(view);
-    assert_pattern_compliance_concreate(&c, &template.content);
+    if let Err(mismatches) = check_pattern_compliance(view, template) {
+        panic!(
+            "Tree does not comply with template {}",
+            describe_all(&mismatches)
+        );
+    }
 }
 
-fn assert_pattern_compliance_concreate(c: &Concrete<u8>, template: &BytePatternChild) {
-    match template {
-        BytePatternChild::List(children) => {
-            match c {
-                Concrete::List(list) => {
-                    assert_eq!(list.len(), children.len());
-                    for i in 0..list.len() {
-                        assert_pattern_compliance_concreate(&list[i], &children[i]);
-                    }
+fn describe_all(mismatches: &[Mismatch]) -> String {
+    mismatches
+        .iter()
+        .map(|mismatch| mismatch.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn describe(c: &Concrete<Vec<u8>>) -> String {
+    match c {
+        Concrete::List(list) => format!("list of {}", list.len()),
+        Concrete::Value(value) => format!("value {:?}", value),
+    }
+}
+
+#[derive(Default)]
+struct Checker {
+    path: Vec<usize>,
+    mismatches: Vec<Mismatch>,
+    /// Byte pattern templates whose ranges have been checked, so each is only reported once (at its first use).
+    checked: HashSet<*const BytePatternTemplate>,
+}
+
+impl Checker {
+    fn result(self) -> Result<(), Vec<Mismatch>> {
+        if self.mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(self.mismatches)
+        }
+    }
+
+    fn mismatch(&mut self, expected: String, actual: String) {
+        self.mismatches.push(Mismatch {
+            path: self.path.clone(),
+            expected,
+            actual,
+        });
+    }
+
+    /// Checks the children of list against templates, reporting a different count.
+    fn check_children<T>(
+        &mut self,
+        list: &[Concrete<Vec<u8>>],
+        templates: &[T],
+        mut check: impl FnMut(&mut Self, &Concrete<Vec<u8>>, &T),
+    ) {
+        if list.len() != templates.len() {
+            self.mismatch(
+                format!("list of {}", templates.len()),
+                format!("list of {}", list.len()),
+            );
+        }
+        for (i, (child, template)) in list.iter().zip(templates).enumerate() {
+            self.path.push(i);
+            check(self, child, template);
+            self.path.pop();
+        }
+    }
+
+    fn check_value(
+        &mut self,
+        c: &Concrete<Vec<u8>>,
+        expected: String,
+        valid: impl Fn(&[u8]) -> bool,
+    ) {
+        match c {
+            Concrete::Value(value) if valid(value) => {}
+            _ => self.mismatch(expected, describe(c)),
+        }
+    }
+
+    fn check_tree(&mut self, c: &Concrete<Vec<u8>>, template: &TreeTemplate) {
+        match template {
+            TreeTemplate::List(children) => match c {
+                Concrete::List(list) => self.check_children(list, children, Self::check_tree),
+                Concrete::Value(_) => {
+                    self.mismatch(format!("list of {}", children.len()), describe(c))
                 }
-                Concrete::Value(_) => panic!(),
-            };
+            },
+            TreeTemplate::ConstantValue(value) => {
+                self.check_value(c, format!("value {:?}", value), |actual| {
+                    actual == &value[..]
+                })
+            }
+            TreeTemplate::ValueFromStream => self.check_value(c, "a value".to_string(), |_| true),
+            TreeTemplate::TreeFromStream => {}
+            TreeTemplate::TreeTemplateUse(template) => self.check_tree(c, template),
+            TreeTemplate::BytePatternTemplateUse(template) => {
+                self.check_pattern_template(c, template)
+            }
         }
-        BytePatternChild::ConstantValue(value) => {
-            match c {
-                Concrete::List(_) => panic!(),
-                Concrete::Value(tree_value) => assert_eq!(value, tree_value),
-            };
+    }
+
+    fn check_pattern_template(&mut self, c: &Concrete<Vec<u8>>, template: &BytePatternTemplate) {
+        self.check_pattern(c, &template.content);
+        self.check_ranges(template);
+    }
+
+    /// Reports values in template which are out of range or overlap, once for each template (at its first use).
+    /// Templates used within it are checked the same way, so their values are not reported again at each use.
+    fn check_ranges(&mut self, template: &BytePatternTemplate) {
+        if !self.checked.insert(template) {
+            return;
         }
-        BytePatternChild::ValueFromStreamAtOffset(_) => {
-            match c {
-                Concrete::List(_) => panic!(),
-                Concrete::Value(_) => {}
-            };
+
+        let mut ranges = vec![];
+        self.pattern_ranges(&template.content, 0, &mut ranges);
+        // Sorted by start, so a range overlaps a previous one if it starts before the end of the furthest reaching one
+        ranges.sort_by_key(|(start, end, _)| (*start, *end));
+        let mut furthest: Option<(u64, u64, Vec<usize>)> = None;
+        for (start, end, path) in ranges {
+            if end > template.size as u64 {
+                self.mismatches.push(Mismatch {
+                    path: path.clone(),
+                    expected: format!("bytes within the size {} of the template", template.size),
+                    actual: format!("bytes {}..{}", start, end),
+                });
+            }
+            if start == end {
+                // Uses no bytes, so can not overlap anything.
+                continue;
+            }
+            match &furthest {
+                Some((furthest_start, furthest_end, furthest_path)) if start < *furthest_end => {
+                    self.mismatches.push(Mismatch {
+                        path: path.clone(),
+                        expected: format!("bytes {}..{} used by no other value", start, end),
+                        actual: format!(
+                            "overlap with bytes {}..{} at {:?}",
+                            furthest_start, furthest_end, furthest_path
+                        ),
+                    });
+                    if end > *furthest_end {
+                        furthest = Some((start, end, path));
+                    }
+                }
+                _ => furthest = Some((start, end, path)),
+            }
         }
-        BytePatternChild::TemplateUse(template) => {
-            assert_pattern_compliance_concreate(c, &template.template.content)
+    }
+
+    fn check_pattern(&mut self, c: &Concrete<Vec<u8>>, template: &BytePatternChild) {
+        match template {
+            BytePatternChild::List(children) => match c {
+                Concrete::List(list) => self.check_children(list, children, Self::check_pattern),
+                Concrete::Value(_) => {
+                    self.mismatch(format!("list of {}", children.len()), describe(c))
+                }
+            },
+            BytePatternChild::ConstantValue(value) => {
+                self.check_value(c, format!("value {:?}", value), |actual| {
+                    actual == &value[..]
+                })
+            }
+            BytePatternChild::ValueFromStreamAtOffset { length, .. } => {
+                self.check_value(c, format!("value of length {}", length), |actual| {
+                    actual.len() == *length as usize
+                })
+            }
+            BytePatternChild::TemplateUse(template_use) => {
+                self.check_pattern(c, &template_use.template.content)
+            }
+        }
+    }
+
+    /// Appends the range of bytes in the stream used by each value from the stream in template (with its path) to ranges.
+    /// offset is where template's bytes start in the stream.
+    /// Each template used within template is one range of its size, and has its own values checked by check_ranges.
+    fn pattern_ranges(
+        &mut self,
+        template: &BytePatternChild,
+        offset: u64,
+        ranges: &mut Vec<(u64, u64, Vec<usize>)>,
+    ) {
+        match template {
+            BytePatternChild::List(children) => {
+                for (i, child) in children.iter().enumerate() {
+                    self.path.push(i);
+                    self.pattern_ranges(child, offset, ranges);
+                    self.path.pop();
+                }
+            }
+            BytePatternChild::ConstantValue(_) => {}
+            BytePatternChild::ValueFromStreamAtOffset {
+                offset: value_offset,
+                length,
+            } => {
+                let start = offset + *value_offset as u64;
+                ranges.push((start, start + *length as u64, self.path.clone()));
+            }
+            BytePatternChild::TemplateUse(template_use) => {
+                self.check_ranges(&template_use.template);
+                let start = offset + template_use.offset as u64;
+                let end = start + template_use.template.size as u64;
+                ranges.push((start, end, self.path.clone()));
+            }
         }
     }
 }
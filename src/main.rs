@@ -1,13 +1,18 @@
 pub mod basic_encoding;
+pub mod container;
 pub mod data_models;
+pub mod incremental_decoding;
 pub mod prefix_encoding;
 pub mod type_to_leaf;
+pub mod varint;
 #[macro_use]
 pub mod into_typed_value_tree;
 pub mod leaf_tree_template;
+#[cfg(test)]
+mod property_tests;
 
 use self::encoding::*;
-use self::prefix_encoding::PrefixCompressedEncoding;
+use self::prefix_encoding::{PrefixCompressedEncoding, SchemaEncoding};
 use self::type_to_leaf::TypeViewer;
 
 fn main() {
@@ -37,6 +42,15 @@ fn main() {
     data_models::typed_value_tree::concrete::view_to_concrete(&data);
     data_models::leaf_tree::concrete::view_to_concrete(&TypeViewer(&data));
 
+    let mut schema_encoded = vec![];
+    SchemaEncoding::<test_data::Color>::new()
+        .write_list(&data.colors, &mut schema_encoded)
+        .unwrap();
+    println!(
+        "exist (colors with schema template) = {}",
+        schema_encoded.len() as f64 / bin_code_size
+    );
+
     let encoded = PrefixCompressedEncoding.serialize(&TypeViewer(&data));
     println!("exist = {}", encoded.len() as f64 / bin_code_size);
 
@@ -57,6 +71,7 @@ fn main() {
 /// Does not implement any encodings, just declare the traits encoders and decoders will implement.
 pub mod encoding {
     use super::data_models::leaf_tree::{View, Visitor};
+    use std::io::{self, Write};
 
     pub struct EncodedLeafTree<TDecoder, Value>
     where
@@ -72,10 +87,65 @@ pub mod encoding {
         fn visit_root<V: Visitor<Value = Self::Value>>(&self, data: &[u8], v: &mut V);
     }
 
+    /// A step in a pre-order traversal of the content of a root node,
+    /// matching the Visitor callbacks: ListStart and ListEnd surround each child node.
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    pub enum Event<Value> {
+        ListStart,
+        ListEnd,
+        Value(Value),
+    }
+
+    // Implement this to define a way to deserialize leaf trees incrementally, as bytes become available.
+    //
+    // Only BasicEncoding and PrefixEncoding implement it. Documents using templates (PrefixCompressedEncoding and its variants)
+    // can reference any earlier subtree, so decoding them holds every template rather than only the current path:
+    // they have to be decoded from a complete buffer with Decoder.
+    pub trait IncrementalDecoder {
+        type Value;
+        /// Decoding progress. Holds only the current path from the root, not the decoded tree.
+        type State: Default;
+
+        /// Decodes from the start of input, returning the next event (if any) and the number of bytes consumed.
+        /// Returns (None, 0) if input does not contain enough bytes to make progress.
+        fn next_event(
+            &self,
+            state: &mut Self::State,
+            input: &[u8],
+        ) -> io::Result<(Option<Event<Self::Value>>, usize)>;
+
+        /// True if the events so far form a complete tree, so the input may end here.
+        fn can_end(&self, state: &Self::State) -> bool;
+    }
+
     // Implement this to define a way to serialize leaf trees.
     pub trait Encoder {
         type Value;
-        fn serialize<TView: View<Value = Self::Value>>(&self, v: &TView) -> Vec<u8>;
+
+        /// Streams the encoding of v to out.
+        /// Writes are small, so unbuffered outputs (like files and sockets) should be wrapped in a BufWriter.
+        fn write<TView: View<Value = Self::Value>, W: Write>(
+            &self,
+            v: &TView,
+            out: &mut W,
+        ) -> io::Result<()>;
+
+        fn serialize<TView: View<Value = Self::Value>>(&self, v: &TView) -> Vec<u8> {
+            let mut out = vec![];
+            self.serialize_into(v, &mut out);
+            out
+        }
+
+        /// Replaces the content of buffer with the encoding of v, reusing its allocation.
+        fn serialize_into<TView: View<Value = Self::Value>>(
+            &self,
+            v: &TView,
+            buffer: &mut Vec<u8>,
+        ) {
+            buffer.clear();
+            self.write(v, buffer)
+                .expect("Writing to a Vec should not fail");
+        }
     }
 
     impl<TDecoder, Value> View for EncodedLeafTree<TDecoder, Value>
@@ -92,11 +162,39 @@ pub mod encoding {
 #[cfg(test)]
 mod tests {
     use super::basic_encoding::BasicEncoding;
+    use super::container::{
+        open, serialize_document, ContainedEncoding, EncodingId, FLAG_CHECKSUM, MAGIC,
+    };
     use super::data_models::leaf_tree::concrete::{view_to_concrete, Concrete};
+    use super::data_models::leaf_tree::{View, Visitor};
+    use super::data_models::typed_value_tree::{MapView, MapVisitor, TypeView, TypeVisitor};
     use super::encoding::*;
-    use super::prefix_encoding::{PrefixCompressedEncoding, PrefixEncoding};
+    use super::into_typed_value_tree::{
+        single_field_schema, visit_single_field, HasSchema, Named, PlainData, Schema, Struct,
+        Terminal,
+    };
+    use super::leaf_tree_template::{
+        assert_compliance, assert_pattern_compliance, check_compliance, check_pattern_compliance,
+        extract_byte_pattern_data, extract_tree_stream, BytePatternChild, BytePatternTemplate,
+        BytePatternView, Mismatch, OffsetTemplateUse, TreeTemplate,
+    };
+    use super::prefix_encoding::{
+        read_plain_data, write_plain_data, CompressionOptions, PrefixCompressedEncoding,
+        PrefixDictionaryEncoding, PrefixEncoding, PrefixSizedEncoding, PrefixTunedEncoding,
+        PrefixWindowedDecoding, PrefixWindowedEncoding, SchemaEncoding, SizedNode,
+        TemplateDictionary, TreeTemplateView, FORMAT_VERSION,
+    };
+    use super::test_data::{Color, TestData};
+    use super::type_to_leaf::{schema_byte_pattern, schema_tree_template, TypeViewer};
+    use std::io::{self, Write};
+    use std::mem::offset_of;
+    use std::num::NonZeroU32;
+    use std::rc::Rc;
 
-    fn encode_round_trip<T: Encoder<Value = u8> + Decoder<Value = u8>>(c: &Concrete<u8>, e: T) {
+    fn encode_round_trip<T: Encoder<Value = Vec<u8>> + Decoder<Value = Vec<u8>>>(
+        c: &Concrete<Vec<u8>>,
+        e: T,
+    ) {
         let input_copy = view_to_concrete(c);
         assert_eq!(&input_copy, c, "copy");
 
@@ -109,17 +207,18 @@ mod tests {
         assert_eq!(&decoded_copy, c, "decode");
     }
 
-    fn check(c: Concrete<u8>, v: Vec<u8>) {
+    fn check(c: Concrete<Vec<u8>>, v: Vec<u8>) {
         encode_round_trip(&c, BasicEncoding);
         let encoded = BasicEncoding.serialize(&c);
         assert_eq!(encoded, v, "encode");
         check2(c);
     }
 
-    fn check2(c: Concrete<u8>) {
+    fn check2(c: Concrete<Vec<u8>>) {
         encode_round_trip(&c, BasicEncoding);
         encode_round_trip(&c, PrefixEncoding);
         encode_round_trip(&c, PrefixCompressedEncoding);
+        encode_round_trip(&c, PrefixSizedEncoding);
     }
 
     #[test]
@@ -129,18 +228,39 @@ mod tests {
 
     #[test]
     fn encode_value() {
-        check(Concrete::Value(12), vec![1, 12]);
+        check(Concrete::Value(vec![12]), vec![1, 12]);
+    }
+
+    #[test]
+    fn encode_bytes() {
+        check(
+            Concrete::Value(vec![1, 2, 3]),
+            vec![3, 3, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3],
+        );
+        check(
+            Concrete::List(vec![Concrete::Value(vec![]), Concrete::Value(vec![4])]),
+            vec![0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1, 4, 2],
+        );
+        check2(Concrete::List(vec![
+            Concrete::Value((0..100).collect()),
+            Concrete::Value((0..63).collect()),
+            Concrete::List(vec![Concrete::Value(vec![5, 6])]),
+            Concrete::List(vec![Concrete::Value(vec![5, 6])]),
+        ]));
     }
 
     #[test]
     fn encode_list() {
-        check(Concrete::List(vec![Concrete::Value(12)]), vec![0, 1, 12, 2]);
+        check(
+            Concrete::List(vec![Concrete::Value(vec![12])]),
+            vec![0, 1, 12, 2],
+        );
     }
 
     #[test]
     fn encode_list2() {
         check(
-            Concrete::List(vec![Concrete::Value(12), Concrete::Value(13)]),
+            Concrete::List(vec![Concrete::Value(vec![12]), Concrete::Value(vec![13])]),
             vec![0, 1, 12, 2, 0, 1, 13, 2],
         );
     }
@@ -148,30 +268,1315 @@ mod tests {
     #[test]
     fn encode_list_dup() {
         check2(Concrete::List(vec![
-            Concrete::List(vec![Concrete::Value(12)]),
-            Concrete::List(vec![Concrete::Value(12)]),
-            Concrete::List(vec![Concrete::Value(12)]),
-            Concrete::List(vec![Concrete::Value(12)]),
+            Concrete::List(vec![Concrete::Value(vec![12])]),
+            Concrete::List(vec![Concrete::Value(vec![12])]),
+            Concrete::List(vec![Concrete::Value(vec![12])]),
+            Concrete::List(vec![Concrete::Value(vec![12])]),
         ]));
     }
 
     #[test]
     fn encode_list_dup2() {
         check2(Concrete::List(vec![
-            Concrete::List(vec![Concrete::Value(12)]),
-            Concrete::List(vec![Concrete::Value(12), Concrete::Value(12)]),
-            Concrete::List(vec![Concrete::Value(12)]),
-            Concrete::List(vec![Concrete::Value(13)]),
+            Concrete::List(vec![Concrete::Value(vec![12])]),
+            Concrete::List(vec![Concrete::Value(vec![12]), Concrete::Value(vec![12])]),
+            Concrete::List(vec![Concrete::Value(vec![12])]),
+            Concrete::List(vec![Concrete::Value(vec![13])]),
         ]));
     }
+
+    #[test]
+    fn encode_nested_empty() {
+        check(
+            Concrete::List(vec![
+                Concrete::List(vec![]),
+                Concrete::List(vec![Concrete::List(vec![])]),
+            ]),
+            vec![0, 2, 0, 0, 2, 2],
+        );
+    }
+
+    #[test]
+    fn encode_long_list() {
+        // Long lists need a list marker larger than the inline one
+        let long = Concrete::List((0..300).map(|i| Concrete::Value(vec![i as u8])).collect());
+        check2(Concrete::List(vec![
+            long.clone(),
+            Concrete::List(vec![long.clone(), Concrete::Value(vec![1])]),
+            long,
+        ]));
+    }
+
+    #[test]
+    fn encode_deep() {
+        let mut c = Concrete::List(vec![Concrete::Value(vec![1]), Concrete::Value(vec![2])]);
+        for _ in 0..1000 {
+            c = Concrete::List(vec![c, Concrete::Value(vec![3])]);
+        }
+        check2(c);
+    }
+
+    #[test]
+    fn encode_byte_patterns() {
+        // Same shape, with a constant field and differing fields
+        let item = |i: u8| {
+            Concrete::List(
+                (0..8)
+                    .map(|j| Concrete::Value(vec![if j == 0 { 7 } else { i + j }]))
+                    .collect(),
+            )
+        };
+        let c = Concrete::List((0..100).map(item).collect());
+        check2(c.clone());
+        let compressed = PrefixCompressedEncoding.serialize(&c);
+        assert!(compressed.len() < PrefixEncoding.serialize(&c).len() * 3 / 4);
+
+        // Shapes nested in other structures, and lists which match the shape but not the pattern's constants
+        let mut list = vec![];
+        for i in 0..20 {
+            list.push(Concrete::List(vec![item(i), item(i), item(i + 1)]));
+            list.push(item(i / 2));
+            list.push(Concrete::List(vec![item(i), Concrete::Value(vec![i])]));
+        }
+        check2(Concrete::List(list));
+    }
+
+    #[test]
+    fn decode_byte_pattern() {
+        let data = vec![
+            131, // list of 3
+            // Template 0: a value from the stream
+            2, 1, // size 1
+            2, 0, 1, // stream value, offset 0, length 1
+            9, // stream
+            // Template 1: the above value, recorded as a subtree
+            // Template 2: a list using template 0
+            2, 3,   // size 3
+            131, // list of 3
+            1, 5, // constant
+            4, 0, 2, // template 0 at offset 2
+            2, 0, 2, // stream value, offset 0, length 2
+            7, 8, 6, // stream
+            // Template 3: the above list
+            4, 2, // use template 2
+            1, 2, 3, // stream
+        ];
+        let decoded = view_to_concrete(&EncodedLeafTree {
+            decoder: PrefixCompressedEncoding,
+            data,
+        });
+        let v = |bytes: &[u8]| Concrete::Value(bytes.to_vec());
+        assert_eq!(
+            decoded,
+            Concrete::List(vec![
+                v(&[9]),
+                Concrete::List(vec![v(&[5]), v(&[6]), v(&[7, 8])]),
+                Concrete::List(vec![v(&[5]), v(&[3]), v(&[1, 2])]),
+            ])
+        );
+    }
+
+    #[test]
+    fn encode_tree_templates() {
+        // Structs with an optional field, which is present in some instances
+        let item = |i: u8| {
+            let optional = if i % 3 == 0 {
+                vec![Concrete::Value(vec![i, i])]
+            } else {
+                vec![]
+            };
+            Concrete::List(vec![
+                Concrete::Value(vec![1; 16]),
+                Concrete::List(vec![
+                    Concrete::Value(vec![2; 16]),
+                    Concrete::List(vec![Concrete::Value(vec![i])]),
+                    Concrete::Value(vec![3; 16]),
+                    Concrete::List(optional),
+                ]),
+            ])
+        };
+        let c = Concrete::List((0..100).map(item).collect());
+        check2(c.clone());
+        let compressed = PrefixCompressedEncoding.serialize(&c);
+        assert!(compressed.len() < PrefixEncoding.serialize(&c).len() / 4);
+
+        // Nested in lists which have the same head, but differ in shape
+        let mut list = vec![];
+        for i in 0..20 {
+            list.push(Concrete::List(vec![item(i), Concrete::Value(vec![i])]));
+            list.push(Concrete::List(vec![
+                Concrete::List(vec![item(i), item(i)]),
+                Concrete::Value(vec![i]),
+            ]));
+        }
+        check2(Concrete::List(list));
+    }
+
+    #[test]
+    fn decode_tree_template() {
+        let data = vec![
+            130, // list of 2
+            // Template 0: a list with a constant and a tree from the stream
+            3, 130, 1, 5, 3, // template
+            1, 6, // tree stream
+            // Template 1: the above list, recorded as a subtree
+            // Template 2: template 0 and a value from the stream
+            3, 130, 4, 0, 2, // template
+            128, 66, 7, 8, // tree stream
+        ];
+        let decoded = view_to_concrete(&EncodedLeafTree {
+            decoder: PrefixCompressedEncoding,
+            data,
+        });
+        let v = |bytes: &[u8]| Concrete::Value(bytes.to_vec());
+        assert_eq!(
+            decoded,
+            Concrete::List(vec![
+                Concrete::List(vec![v(&[5]), v(&[6])]),
+                Concrete::List(vec![
+                    Concrete::List(vec![v(&[5]), Concrete::List(vec![])]),
+                    v(&[7, 8]),
+                ]),
+            ])
+        );
+    }
+
+    #[test]
+    fn encode_template_sequences() {
+        let item = |i: u8| {
+            Concrete::List(
+                (0..8)
+                    .map(|j| Concrete::Value(vec![if j == 0 { 7 } else { i + j }]))
+                    .collect(),
+            )
+        };
+        let c = Concrete::List((0..100).map(item).collect());
+        check2(c.clone());
+        let compressed = PrefixCompressedEncoding.serialize(&c);
+        assert!(compressed.len() < PrefixEncoding.serialize(&c).len() / 2);
+
+        // Runs broken up by other nodes, and runs at the end of lists
+        let mut list = vec![];
+        for i in 0..60 {
+            list.push(item(i));
+            if i % 7 == 0 {
+                list.push(Concrete::Value(vec![i]));
+            }
+        }
+        check2(Concrete::List(vec![
+            Concrete::List(list),
+            Concrete::List((0..30).map(item).collect()),
+        ]));
+    }
+
+    #[test]
+    fn decode_template_sequence() {
+        let data = vec![
+            133, // list of 5
+            // Template 0: a value from the stream
+            2, 1, 2, 0, 1, 9,
+            // Template 1: the above value, recorded as a subtree
+            // Two uses of template 0
+            5, 0, 2, 3, 4,
+            // Templates 2 and 3: the above values, recorded as subtrees
+            // Template 4: a byte pattern with a sequence of template 0
+            2, 3, 131, // size 3, list of 3
+            5, 0, 2, 1, // template 0, twice, at offset 1
+            1, 7, // constant
+            0, 5, 6, // stream
+            // Template 5: the above list, recorded as a subtree
+            // A tree template with a sequence of template 1
+            3, 131, 5, 1, 2, 2, // template
+            1, 8, // tree stream
+        ];
+        let decoded = view_to_concrete(&EncodedLeafTree {
+            decoder: PrefixCompressedEncoding,
+            data,
+        });
+        let v = |bytes: &[u8]| Concrete::Value(bytes.to_vec());
+        assert_eq!(
+            decoded,
+            Concrete::List(vec![
+                v(&[9]),
+                v(&[3]),
+                v(&[4]),
+                Concrete::List(vec![v(&[5]), v(&[6]), v(&[7])]),
+                Concrete::List(vec![v(&[9]), v(&[9]), v(&[8])]),
+            ])
+        );
+    }
+
+    #[test]
+    fn encode_template_columns() {
+        let item = |i: u8| {
+            Concrete::List(vec![
+                Concrete::Value(vec![7]),
+                Concrete::Value(vec![i]),
+                Concrete::Value(vec![100 + i]),
+            ])
+        };
+        let c = Concrete::List((0..50).map(item).collect());
+        check2(c.clone());
+        // After the first two items (which define the byte pattern), each field is stored contiguously
+        let columns: Vec<u8> = (2..50).chain(102..150).collect();
+        let compressed = PrefixCompressedEncoding.serialize(&c);
+        assert!(compressed.windows(columns.len()).any(|w| w == &columns[..]));
+    }
+
+    #[test]
+    fn encode_shapes() {
+        // The second field is the same in the first few items, so it starts as a constant in the byte pattern
+        let item = |i: u8| {
+            Concrete::List(vec![
+                Concrete::Value(vec![i]),
+                Concrete::Value(vec![if i < 10 { 0 } else { i }, 1]),
+                Concrete::Value(b"constant".to_vec()),
+            ])
+        };
+        let c = Concrete::List((0..100).map(item).collect());
+        check2(c.clone());
+        let compressed = PrefixCompressedEncoding.serialize(&c);
+        // Each item after the first is written as a use of a pattern for the shape, so takes about as many bytes as its varying values
+        assert!(compressed.len() < 100 * 3 + 40);
+    }
+
+    #[test]
+    fn encode_deep_shared() {
+        // Every level is a distinct subtree holding all the levels below it, and each is stored once by reference to its children
+        let mut c = Concrete::List(vec![]);
+        for i in 0..500u32 {
+            c = Concrete::List(vec![Concrete::Value(i.to_le_bytes().to_vec()), c]);
+        }
+        let twice = Concrete::List(vec![c.clone(), c]);
+        check2(twice.clone());
+        let compressed = PrefixCompressedEncoding.serialize(&twice);
+        let prefix = PrefixEncoding.serialize(&twice);
+        // The second copy is a single template use
+        assert!(compressed.len() < prefix.len() / 2 + 10);
+    }
+
+    #[test]
+    fn encode_patched() {
+        // Versions of a record, where the timestamp grows and the nested status changes
+        let record = |timestamp: &[u8], status: u8| {
+            let mut fields: Vec<_> = (0..8).map(|i| Concrete::Value(vec![i])).collect();
+            fields.push(Concrete::Value(timestamp.to_vec()));
+            fields.push(Concrete::List(vec![
+                Concrete::Value(vec![status]),
+                Concrete::Value(vec![9]),
+            ]));
+            Concrete::List(fields)
+        };
+        let first = record(&[1], 0);
+        let c = Concrete::List(vec![
+            first.clone(),
+            record(&[1, 2], 0),
+            record(&[1, 2, 3], 1),
+        ]);
+        check2(c.clone());
+        let compressed = PrefixCompressedEncoding.serialize(&c);
+        // Each version after the first has different value lengths, so is written as the previous one with its changed values:
+        // the marker, template index, replacement count, then each position and value
+        let plain = PrefixCompressedEncoding.serialize(&first).len();
+        assert_eq!(
+            compressed.len(),
+            1 + plain + (3 + 1 + 3) + (3 + 1 + 4 + 1 + 2)
+        );
+    }
+
+    #[test]
+    fn decode_patched() {
+        let data = vec![
+            130, // list of 2
+            // Template 1: the list (after template 0, its last child)
+            131, 65, 1, 65, 2, 130, 65, 3, 65, 4, //
+            // Template 1 with 2 replacements: skip 1 value then replace, skip 1 value then replace
+            9, 1, 2, 1, 65, 5, 1, 65, 6,
+        ];
+        let decoded = view_to_concrete(&EncodedLeafTree {
+            decoder: PrefixCompressedEncoding,
+            data,
+        });
+        let v = |b: u8| Concrete::Value(vec![b]);
+        let item =
+            |a: u8, b: u8| Concrete::List(vec![v(1), v(a), Concrete::List(vec![v(3), v(b)])]);
+        assert_eq!(decoded, Concrete::List(vec![item(2, 4), item(5, 6)]));
+    }
+
+    #[test]
+    fn decode_template_columns() {
+        let data = vec![
+            131, // list of 3
+            // Template 0: a byte pattern
+            2, 3, 131, // size 3, list of 3
+            1, 7, // constant
+            2, 0, 1, // stream
+            2, 1, 2, // stream
+            10, 20, 30, // data stream
+            // Template 1: the above list, recorded as a subtree
+            // Two uses of template 0, as columns
+            7, 0, 2, //
+            11, 12, // first value from the stream
+            21, 31, 22, 32, // second value from the stream
+        ];
+        let decoded = view_to_concrete(&EncodedLeafTree {
+            decoder: PrefixCompressedEncoding,
+            data,
+        });
+        let item = |a: u8, b: &[u8]| {
+            Concrete::List(vec![
+                Concrete::Value(vec![7]),
+                Concrete::Value(vec![a]),
+                Concrete::Value(b.to_vec()),
+            ])
+        };
+        assert_eq!(
+            decoded,
+            Concrete::List(vec![
+                item(10, &[20, 30]),
+                item(11, &[21, 31]),
+                item(12, &[22, 32]),
+            ])
+        );
+    }
+
+    fn colors(count: usize) -> Vec<Color> {
+        (0..count)
+            .map(|i| Color {
+                r: i as u8,
+                g: (i * 3) as u8,
+                b: 7,
+                a: 255,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn schema_templates() {
+        let color = &colors(1)[0];
+        let pattern = schema_byte_pattern(&Color::schema()).unwrap();
+        assert_eq!(pattern.size, 4);
+        assert_pattern_compliance(&TypeViewer(color), &pattern);
+        assert_compliance(&TypeViewer(color), &schema_tree_template(&Color::schema()));
+
+        // List fields vary in length
+        assert!(schema_byte_pattern(&TestData::schema()).is_none());
+        let data = TestData { colors: colors(3) };
+        assert_compliance(
+            &TypeViewer(&data),
+            &schema_tree_template(&TestData::schema()),
+        );
+    }
+
+    #[test]
+    fn compliance_mismatches() {
+        let v = |bytes: &[u8]| Concrete::Value(bytes.to_vec());
+        let stream = |offset, length| BytePatternChild::ValueFromStreamAtOffset { offset, length };
+        // The second value overlaps the first
+        let pattern = Rc::new(BytePatternTemplate {
+            size: 3,
+            content: BytePatternChild::List(vec![stream(0, 2), stream(1, 1)]),
+        });
+        let template = TreeTemplate::List(vec![
+            TreeTemplate::ConstantValue(vec![1]),
+            TreeTemplate::ValueFromStream,
+            TreeTemplate::List(vec![TreeTemplate::TreeFromStream]),
+            TreeTemplate::BytePatternTemplateUse(pattern.clone()),
+        ]);
+        let tree = |first: u8, second: Concrete<Vec<u8>>| {
+            Concrete::List(vec![
+                v(&[first]),
+                second,
+                Concrete::List(vec![Concrete::List(vec![])]),
+                Concrete::List(vec![v(&[1, 2]), v(&[3])]),
+            ])
+        };
+        let paths = |result: Result<(), Vec<Mismatch>>| -> Vec<Vec<usize>> {
+            result
+                .unwrap_err()
+                .into_iter()
+                .map(|mismatch| mismatch.path)
+                .collect()
+        };
+
+        assert_eq!(
+            paths(check_compliance(&tree(1, v(&[5])), &template)),
+            vec![vec![3, 1]]
+        );
+        let mismatches = check_compliance(&tree(2, Concrete::List(vec![])), &template).unwrap_err();
+        assert_eq!(
+            mismatches[0],
+            Mismatch {
+                path: vec![0],
+                expected: "value [1]".to_string(),
+                actual: "value [2]".to_string(),
+            }
+        );
+        assert_eq!(mismatches[1].path, vec![1]);
+        assert_eq!(mismatches.len(), 3);
+
+        // Nested byte pattern uses
+        let outer = BytePatternTemplate {
+            size: 3,
+            content: BytePatternChild::List(vec![
+                stream(0, 1),
+                BytePatternChild::TemplateUse(OffsetTemplateUse {
+                    template: pattern,
+                    offset: 2,
+                }),
+            ]),
+        };
+        let tree = Concrete::List(vec![v(&[0]), Concrete::List(vec![v(&[1, 2]), v(&[3, 4])])]);
+        // [1, 1] has the wrong length, the nested values overlap (reported once, within the used template),
+        // and the use is past the end of the outer template
+        assert_eq!(
+            paths(check_pattern_compliance(&tree, &outer)),
+            vec![vec![1, 1], vec![1, 1], vec![1]]
+        );
+        // Problems with the template are found even where the tree does not match it
+        let tree = Concrete::List(vec![v(&[0]), Concrete::List(vec![v(&[1, 2])])]);
+        assert_eq!(
+            paths(check_pattern_compliance(&tree, &outer)),
+            vec![vec![1], vec![1, 1], vec![1]]
+        );
+    }
+
+    #[test]
+    fn compliance_pattern_checked_once() {
+        let v = |bytes: &[u8]| Concrete::Value(bytes.to_vec());
+        let stream = |offset, length| BytePatternChild::ValueFromStreamAtOffset { offset, length };
+        // Overlapping values, and an empty value within another (which overlaps nothing)
+        let pattern = Rc::new(BytePatternTemplate {
+            size: 2,
+            content: BytePatternChild::List(vec![stream(0, 2), stream(1, 1), stream(1, 0)]),
+        });
+        let use_pattern = || TreeTemplate::BytePatternTemplateUse(pattern.clone());
+        let template = TreeTemplate::List(vec![use_pattern(), use_pattern()]);
+        let used = Concrete::List(vec![v(&[1, 2]), v(&[2]), v(&[])]);
+        let mismatches =
+            check_compliance(&Concrete::List(vec![used.clone(), used.clone()]), &template)
+                .unwrap_err();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].path, vec![0, 1]);
+
+        // Including where it is used within another byte pattern
+        let use_nested = |offset| {
+            BytePatternChild::TemplateUse(OffsetTemplateUse {
+                template: pattern.clone(),
+                offset,
+            })
+        };
+        let outer = BytePatternTemplate {
+            size: 4,
+            content: BytePatternChild::List(vec![use_nested(0), use_nested(2)]),
+        };
+        let mismatches =
+            check_pattern_compliance(&Concrete::List(vec![used.clone(), used]), &outer)
+                .unwrap_err();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].path, vec![0, 1]);
+    }
+
+    #[test]
+    fn template_views() {
+        let v = |bytes: &[u8]| Concrete::Value(bytes.to_vec());
+        let stream = |offset, length| BytePatternChild::ValueFromStreamAtOffset { offset, length };
+        let inner = Rc::new(BytePatternTemplate {
+            size: 2,
+            content: BytePatternChild::List(vec![
+                stream(0, 1),
+                BytePatternChild::ConstantValue(vec![9]),
+                stream(1, 1),
+            ]),
+        });
+        let outer = BytePatternTemplate {
+            size: 4,
+            content: BytePatternChild::List(vec![
+                stream(0, 1),
+                BytePatternChild::TemplateUse(OffsetTemplateUse {
+                    template: inner.clone(),
+                    offset: 2,
+                }),
+                stream(1, 1),
+            ]),
+        };
+        assert_eq!(
+            view_to_concrete(&BytePatternView::new(&outer, &[1, 2, 3, 4])),
+            Concrete::List(vec![
+                v(&[1]),
+                Concrete::List(vec![v(&[3]), v(&[9]), v(&[4])]),
+                v(&[2]),
+            ])
+        );
+
+        let template = TreeTemplate::List(vec![
+            TreeTemplate::ConstantValue(vec![7]),
+            TreeTemplate::ValueFromStream,
+            TreeTemplate::BytePatternTemplateUse(inner),
+            TreeTemplate::List(vec![
+                TreeTemplate::TreeFromStream,
+                TreeTemplate::TreeTemplateUse(Rc::new(TreeTemplate::List(vec![
+                    TreeTemplate::ValueFromStream,
+                ]))),
+            ]),
+        ]);
+        let mut tree_stream = PrefixEncoding.serialize(&v(&[5]));
+        tree_stream.extend_from_slice(&[1, 2]);
+        tree_stream.extend(PrefixEncoding.serialize(&Concrete::List(vec![v(&[6])])));
+        tree_stream.extend(PrefixEncoding.serialize(&v(&[8])));
+        let view = TreeTemplateView::new(&template, &tree_stream).unwrap();
+        let last = Concrete::List(vec![
+            Concrete::List(vec![v(&[6])]),
+            Concrete::List(vec![v(&[8])]),
+        ]);
+        assert_eq!(
+            view_to_concrete(&view),
+            Concrete::List(vec![
+                v(&[7]),
+                v(&[5]),
+                Concrete::List(vec![v(&[1]), v(&[9]), v(&[2])]),
+                last.clone(),
+            ])
+        );
+
+        // Children which are not visited are skipped
+        struct LastChild(usize, Option<Concrete<Vec<u8>>>);
+        impl Visitor for LastChild {
+            type Value = Vec<u8>;
+            fn visit_list<T: View<Value = Vec<u8>>>(&mut self, t: &T) {
+                self.0 += 1;
+                if self.0 == 4 {
+                    self.1 = Some(view_to_concrete(t));
+                }
+            }
+            fn visit_value(&mut self, _t: Vec<u8>) {}
+        }
+        assert_eq!(view.apply(LastChild(0, None)).1, Some(last));
+
+        // Streams which do not fit the template are rejected
+        let invalid = |stream: &[u8]| {
+            TreeTemplateView::new(&template, stream)
+                .err()
+                .unwrap()
+                .kind()
+        };
+        let mut trailing = tree_stream.clone();
+        trailing.push(0);
+        assert_eq!(invalid(&trailing), io::ErrorKind::InvalidData);
+        assert_eq!(
+            invalid(&tree_stream[..tree_stream.len() - 1]),
+            io::ErrorKind::UnexpectedEof
+        );
+        let mut list_for_value = PrefixEncoding.serialize(&Concrete::List(vec![]));
+        list_for_value.extend_from_slice(&tree_stream[2..]);
+        assert_eq!(invalid(&list_for_value), io::ErrorKind::InvalidData);
+        // Holes are PrefixEncoding nodes, so can not use PrefixCompressedEncoding's markers
+        let mut bad_marker = tree_stream[..4].to_vec();
+        bad_marker.push(3);
+        assert_eq!(invalid(&bad_marker), io::ErrorKind::InvalidData);
+
+        // Extracting the streams from the trees is the inverse
+        let tree = view_to_concrete(&view);
+        assert_eq!(extract_tree_stream(&tree, &template).unwrap(), tree_stream);
+        let tree = view_to_concrete(&BytePatternView::new(&outer, &[1, 2, 3, 4]));
+        assert_eq!(
+            extract_byte_pattern_data(&tree, &outer).unwrap(),
+            vec![1, 2, 3, 4]
+        );
+        let mismatches = extract_tree_stream(&v(&[7]), &template).unwrap_err();
+        assert_eq!(mismatches[0].path, Vec::<usize>::new());
+        let mismatches = extract_byte_pattern_data(
+            &Concrete::List(vec![v(&[1]), v(&[3, 9, 4]), v(&[2])]),
+            &outer,
+        )
+        .unwrap_err();
+        assert_eq!(mismatches[0].path, vec![1]);
+    }
+
+    #[test]
+    fn schema_encoding() {
+        let decode = |data: Vec<u8>| {
+            view_to_concrete(&EncodedLeafTree {
+                decoder: PrefixCompressedEncoding,
+                data,
+            })
+        };
+        for count in [0, 1, 2, 100] {
+            let colors = colors(count);
+            let mut encoded = vec![];
+            SchemaEncoding::<Color>::new()
+                .write_list(&colors, &mut encoded)
+                .unwrap();
+            // After the template, each color is written as just its 4 bytes
+            if count > 1 {
+                assert!(encoded.len() < 200 + 4 * count);
+            }
+            assert_eq!(
+                decode(encoded),
+                Concrete::List(
+                    colors
+                        .iter()
+                        .map(|color| view_to_concrete(&TypeViewer(color)))
+                        .collect()
+                )
+            );
+        }
+
+        let data = TestData { colors: colors(5) };
+        let mut encoded = vec![];
+        SchemaEncoding::<TestData>::new()
+            .write(&data, &mut encoded)
+            .unwrap();
+        assert_eq!(decode(encoded), view_to_concrete(&TypeViewer(&data)));
+    }
+
+    TypeViewForTerminal!(u32);
+    impl Terminal for u32 {
+        fn get_id() -> u128 {
+            4
+        }
+
+        fn bytes(&self) -> Vec<u8> {
+            self.to_le_bytes().to_vec()
+        }
+    }
+
+    impl HasSchema for u32 {
+        fn schema() -> Schema {
+            Schema::Terminal {
+                id: <u32 as Terminal>::get_id(),
+                size: Some(4),
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    #[repr(C)]
+    struct Sample {
+        time: u32,
+        value: u32,
+        level: u8,
+        flags: [u8; 3],
+    }
+
+    TypeViewForStruct!(Sample);
+    impl Struct for Sample {
+        fn get_id() -> u128 {
+            5
+        }
+
+        fn visit<V: MapVisitor<N = u128>>(&self, v: &mut V) {
+            visit_single_field(v, &10u128, &self.time);
+            visit_single_field(v, &11u128, &self.value);
+            visit_single_field(v, &12u128, &self.level);
+            visit_single_field(v, &13u128, &self.flags[0]);
+            visit_single_field(v, &14u128, &self.flags[1]);
+            visit_single_field(v, &15u128, &self.flags[2]);
+        }
+    }
+
+    impl HasSchema for Sample {
+        fn schema() -> Schema {
+            Schema::Struct {
+                id: <Self as Struct>::get_id(),
+                fields: vec![
+                    single_field_schema::<u32>(10),
+                    single_field_schema::<u32>(11),
+                    single_field_schema::<u8>(12),
+                    single_field_schema::<u8>(13),
+                    single_field_schema::<u8>(14),
+                    single_field_schema::<u8>(15),
+                ],
+            }
+        }
+    }
+
+    unsafe impl PlainData for Sample {
+        fn terminal_offsets() -> Vec<usize> {
+            let flags = offset_of!(Sample, flags);
+            vec![
+                offset_of!(Sample, time),
+                offset_of!(Sample, value),
+                offset_of!(Sample, level),
+                flags,
+                flags + 1,
+                flags + 2,
+            ]
+        }
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn plain_data() {
+        let samples: Vec<Sample> = (0..50)
+            .map(|i| Sample {
+                time: i * 100_000,
+                value: i * 7,
+                level: i as u8,
+                flags: [1, 2, i as u8],
+            })
+            .collect();
+        let mut encoded = vec![];
+        write_plain_data(&samples, &mut encoded).unwrap();
+        let expected_stream: Vec<u8> = samples
+            .iter()
+            .flat_map(|sample| {
+                let mut bytes = sample.time.to_le_bytes().to_vec();
+                bytes.extend_from_slice(&sample.value.to_le_bytes());
+                bytes.push(sample.level);
+                bytes.extend_from_slice(&sample.flags);
+                bytes
+            })
+            .collect();
+        assert!(encoded.ends_with(&expected_stream));
+
+        // A normal document
+        assert_eq!(
+            view_to_concrete(&EncodedLeafTree {
+                decoder: PrefixCompressedEncoding,
+                data: encoded.clone(),
+            }),
+            Concrete::List(
+                samples
+                    .iter()
+                    .map(|sample| view_to_concrete(&TypeViewer(sample)))
+                    .collect()
+            )
+        );
+
+        // Decoded in place, from a buffer aligned for Sample
+        let mut buffer = vec![0u32; encoded.len() / 4 + 1];
+        let aligned = unsafe {
+            std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, encoded.len() + 1)
+        };
+        aligned[..encoded.len()].copy_from_slice(&encoded);
+        let decoded = read_plain_data::<Sample>(&aligned[..encoded.len()]).unwrap();
+        assert_eq!(decoded, &samples[..]);
+        assert_eq!(
+            decoded.as_ptr() as usize,
+            aligned.as_ptr() as usize + encoded.len() - expected_stream.len()
+        );
+
+        // Not aligned
+        aligned.copy_within(..encoded.len(), 1);
+        let error = read_plain_data::<Sample>(&aligned[1..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        // Another type, or truncated
+        let error = read_plain_data::<Color>(&encoded).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = read_plain_data::<Sample>(&encoded[..encoded.len() - 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Types with alignment 1 never need copying
+        let colors = colors(10);
+        let mut encoded = vec![];
+        write_plain_data(&colors, &mut encoded).unwrap();
+        assert_eq!(read_plain_data::<Color>(&encoded).unwrap(), &colors[..]);
+
+        let mut encoded = vec![];
+        write_plain_data::<Color, _>(&[], &mut encoded).unwrap();
+        assert_eq!(read_plain_data::<Color>(&encoded).unwrap(), &[]);
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn plain_data_malformed() {
+        let colors = colors(10);
+        let mut encoded = vec![];
+        write_plain_data(&colors, &mut encoded).unwrap();
+        // The list marker, BYTE_PATTERN_TEMPLATE_SEQUENCE_MARKER, the pattern's size, the pattern,
+        // the repeat count, then the padding (none, since Color has alignment 1) before the 4 bytes of each color.
+        let header = &encoded[..encoded.len() - 40];
+        assert_eq!(header[2], 4);
+        assert_eq!(header.last(), Some(&0));
+
+        let check = |data: &[u8], kind: io::ErrorKind| {
+            assert_eq!(read_plain_data::<Color>(data).unwrap_err().kind(), kind);
+        };
+
+        // Truncated within the pattern
+        check(&encoded[..3], io::ErrorKind::UnexpectedEof);
+
+        // A pattern reading past its size
+        let mut small = encoded.clone();
+        small[2] = 3;
+        check(&small, io::ErrorKind::InvalidData);
+
+        // Not a byte pattern
+        let mut garbage = encoded[..3].to_vec();
+        garbage.extend_from_slice(&[3, 200, 200]);
+        check(&garbage, io::ErrorKind::InvalidData);
+
+        // Padding past the end of the document, including overflowing the position
+        for padding in &[41u64, u64::MAX] {
+            let mut padded = header[..header.len() - 1].to_vec();
+            super::varint::write_varint(&mut padded, *padding).unwrap();
+            padded.extend_from_slice(&encoded[header.len()..]);
+            check(&padded, io::ErrorKind::InvalidData);
+        }
+    }
+
+    /// Counts the children of the root.
+    struct Count(usize);
+
+    impl Visitor for Count {
+        type Value = Vec<u8>;
+        fn visit_list<T: View<Value = Vec<u8>>>(&mut self, _t: &T) {
+            self.0 += 1;
+        }
+        fn visit_value(&mut self, _t: Vec<u8>) {}
+    }
+
+    fn message(i: u8) -> Concrete<Vec<u8>> {
+        Concrete::List(vec![
+            Concrete::Value(b"message type".to_vec()),
+            Concrete::List(vec![
+                Concrete::Value(vec![i]),
+                Concrete::Value(vec![i / 2, 7]),
+                Concrete::Value(b"sender name".to_vec()),
+            ]),
+        ])
+    }
+
+    fn message_dictionary() -> Rc<TemplateDictionary> {
+        let samples: Vec<_> = (0..5).map(message).collect();
+        Rc::new(TemplateDictionary::load(&TemplateDictionary::train(&samples)).unwrap())
+    }
+
+    #[test]
+    fn compressed_expansion_limit() {
+        use super::varint::write_varint;
+        // A list of a recorded value (template 0), followed by data expanding it 2^32 - 1 times
+        let document = |count: u64, rest: &[u8]| {
+            let mut data = vec![0];
+            write_varint(&mut data, count).unwrap();
+            data.extend_from_slice(&[64 + 5, 1, 2, 3, 4, 5]);
+            data.extend_from_slice(rest);
+            data
+        };
+        let mut sequence = vec![5, 0];
+        write_varint(&mut sequence, u32::MAX as u64).unwrap();
+        let mut tree_template = vec![3, 0];
+        write_varint(&mut tree_template, u32::MAX as u64).unwrap();
+        tree_template.extend_from_slice(&sequence);
+
+        // Loading decodes without any other checks first, and fails instead of running out of memory
+        for data in [document(1 << 32, &sequence), document(2, &tree_template)] {
+            let error = TemplateDictionary::load(&data).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn dictionary_encoding() {
+        let e = PrefixDictionaryEncoding {
+            dictionary: message_dictionary(),
+            options: CompressionOptions::default(),
+        };
+        for i in [3, 20] {
+            let c = message(i);
+            encode_round_trip(&c, e.clone());
+            // The dictionary id, then a template use and the varying values
+            assert!(e.serialize(&c).len() <= 8 + 8);
+            assert!(PrefixCompressedEncoding.serialize(&c).len() > 30);
+        }
+
+        // Only documents encoded as PrefixCompressedEncoding would encode them can be dictionaries
+        let c = Concrete::List(vec![
+            Concrete::Value(vec![1; 5]),
+            Concrete::Value(vec![1; 5]),
+        ]);
+        let error = TemplateDictionary::load(&PrefixEncoding.serialize(&c))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Malformed dictionaries are errors too
+        let dictionary = TemplateDictionary::train(&[message(1), message(2)]);
+        for length in 0..dictionary.len() {
+            assert!(TemplateDictionary::load(&dictionary[..length]).is_err());
+        }
+        for invalid in [vec![3], vec![129, 4, 7], vec![129, 9, 0, 0]] {
+            assert!(TemplateDictionary::load(&invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn dictionary_mismatch() {
+        let e = PrefixDictionaryEncoding {
+            dictionary: message_dictionary(),
+            options: CompressionOptions::default(),
+        };
+        let mut encoded = e.serialize(&message(1));
+        let mut out = Count(0);
+        e.try_visit_root(&encoded, &mut out).unwrap();
+        assert_eq!(out.0, 2);
+
+        // Nothing is visited if decoding fails
+        let mut out = Count(0);
+        let error = e
+            .try_visit_root(&encoded[..encoded.len() - 1], &mut out)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        encoded[0] ^= 1;
+        let error = e.try_visit_root(&encoded, &mut out).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(out.0, 0);
+    }
+
+    #[test]
+    fn schema_dictionary() {
+        // List fields have no schema, so the types of their items are listed too
+        let schemas = || [TestData::schema(), Color::schema()];
+        let dictionary = Rc::new(TemplateDictionary::from_schemas(&schemas()));
+        let e = PrefixDictionaryEncoding {
+            dictionary: dictionary.clone(),
+            options: CompressionOptions::default(),
+        };
+
+        // The dictionary id, then a template use and the color's bytes
+        let color = view_to_concrete(&TypeViewer(&colors(1)[0]));
+        encode_round_trip(&color, e.clone());
+        assert_eq!(e.serialize(&color).len(), 8 + 2 + 4);
+
+        // Type ids and field names are never written
+        for count in [0, 1, 5] {
+            let data = view_to_concrete(&TypeViewer(&TestData {
+                colors: colors(count),
+            }));
+            encode_round_trip(&data, e.clone());
+            assert!(e.serialize(&data).len() <= 8 + 10 + 4 * count);
+        }
+
+        // Documents identify the schemas they were written with
+        let encoded = e.serialize(&color);
+        assert!(dictionary.is_used_by(&encoded));
+        assert_eq!(
+            TemplateDictionary::from_schemas(&schemas()).id(),
+            dictionary.id()
+        );
+        let colors_only = TemplateDictionary::from_schemas(&[Color::schema()]);
+        assert_ne!(colors_only.id(), dictionary.id());
+        assert!(!colors_only.is_used_by(&encoded));
+        assert!(!dictionary.is_used_by(&encoded[..4]));
+    }
+
+    #[test]
+    fn windowed_encoding() {
+        let log = Concrete::List((0..200).map(|i| message(i as u8)).collect());
+        let compressed = PrefixCompressedEncoding.serialize(&log);
+        for max_templates in [1, 2, 3, 10, 1000] {
+            let e = PrefixWindowedEncoding {
+                max_templates: NonZeroU32::new(max_templates).unwrap(),
+                options: CompressionOptions::default(),
+            };
+            encode_round_trip(&log, e.clone());
+            let encoded = e.serialize(&log);
+            if max_templates == 1000 {
+                // Nothing is evicted, so only the window is added
+                assert_eq!(encoded[2..], compressed[..]);
+            } else {
+                // Evicted templates are defined again
+                assert!(encoded.len() > compressed.len());
+            }
+        }
+
+        // The decoder uses the window from the document
+        let e = PrefixWindowedEncoding {
+            max_templates: NonZeroU32::new(3).unwrap(),
+            options: CompressionOptions::default(),
+        };
+        let mut encoded = e.serialize(&log);
+        let decoded = view_to_concrete(&EncodedLeafTree {
+            decoder: PrefixWindowedDecoding,
+            data: encoded.clone(),
+        });
+        assert_eq!(decoded, log);
+
+        // An empty window is an error
+        encoded[0] = 0;
+        let mut out = Count(0);
+        let error = PrefixWindowedDecoding
+            .try_visit_root(&encoded, &mut out)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(out.0, 0);
+    }
+
+    #[test]
+    fn compression_options() {
+        // Referencing an empty list would take more bytes than writing it again
+        let empty = Concrete::List(vec![Concrete::List(vec![]), Concrete::List(vec![])]);
+        assert_eq!(
+            PrefixCompressedEncoding.serialize(&empty),
+            vec![130, 128, 128]
+        );
+
+        let log = Concrete::List((0..50).map(|i| message(i as u8)).collect());
+        let compressed = PrefixCompressedEncoding.serialize(&log);
+        assert_eq!(
+            PrefixTunedEncoding::default().serialize(&log),
+            compressed,
+            "default options"
+        );
+
+        // Without any optimizations, the output is PrefixEncoding
+        let plain = PrefixTunedEncoding {
+            options: CompressionOptions {
+                subtree_templates: false,
+                byte_patterns: false,
+                tree_templates: false,
+                patches: false,
+                sequences: false,
+                columns: false,
+                ..CompressionOptions::default()
+            },
+        };
+        encode_round_trip(&log, plain.clone());
+        assert_eq!(plain.serialize(&log), PrefixEncoding.serialize(&log));
+
+        // Each option can be used on its own, and the output decoded as PrefixCompressedEncoding
+        let mut sizes = vec![];
+        for min_savings in [1, 10, 1000] {
+            let e = PrefixTunedEncoding {
+                options: CompressionOptions {
+                    min_savings,
+                    ..CompressionOptions::default()
+                },
+            };
+            encode_round_trip(&log, e.clone());
+            let encoded = e.serialize(&log);
+            let decoded = view_to_concrete(&EncodedLeafTree {
+                decoder: PrefixCompressedEncoding,
+                data: encoded.clone(),
+            });
+            assert_eq!(decoded, log);
+            sizes.push(encoded.len());
+        }
+        // Requiring larger savings uses fewer templates
+        assert!(sizes[0] <= sizes[1] && sizes[1] < sizes[2]);
+        assert_eq!(sizes[2], PrefixEncoding.serialize(&log).len());
+    }
+
+    fn check_document<E: ContainedEncoding>(
+        e: E,
+        c: &Concrete<Vec<u8>>,
+        dictionaries: &[Rc<TemplateDictionary>],
+    ) {
+        for flags in [0, FLAG_CHECKSUM] {
+            let data = serialize_document(&e, flags, c);
+            let mut header = MAGIC.to_vec();
+            header.extend_from_slice(&[FORMAT_VERSION, e.encoding_id() as u8, flags as u8]);
+            assert_eq!(data[..header.len()], header[..]);
+
+            let document = open(&data, dictionaries).unwrap();
+            assert_eq!(document.header.encoding, e.encoding_id());
+            assert_eq!(document.header.flags, flags);
+            assert_eq!(&view_to_concrete(&document), c);
+        }
+    }
+
+    #[test]
+    fn container_documents() {
+        let c = Concrete::List((0..10).map(message).collect());
+        let dictionaries = [message_dictionary()];
+        check_document(BasicEncoding, &c, &[]);
+        check_document(PrefixEncoding, &c, &[]);
+        check_document(PrefixCompressedEncoding, &c, &[]);
+        check_document(PrefixTunedEncoding::default(), &c, &[]);
+        check_document(PrefixSizedEncoding, &c, &[]);
+        let e = PrefixWindowedEncoding {
+            max_templates: NonZeroU32::new(3).unwrap(),
+            options: CompressionOptions::default(),
+        };
+        check_document(e, &c, &[]);
+        let e = PrefixDictionaryEncoding {
+            dictionary: dictionaries[0].clone(),
+            options: CompressionOptions::default(),
+        };
+        check_document(e.clone(), &c, &dictionaries);
+
+        let kind = |data: &[u8], dictionaries: &[Rc<TemplateDictionary>]| {
+            open(data, dictionaries).err().unwrap().kind()
+        };
+        let data = serialize_document(&PrefixCompressedEncoding, FLAG_CHECKSUM, &c);
+        assert_eq!(kind(&data[..5], &[]), io::ErrorKind::InvalidData);
+        assert_eq!(
+            kind(&PrefixCompressedEncoding.serialize(&c), &[]),
+            io::ErrorKind::InvalidData
+        );
+        let mut corrupted = data.clone();
+        corrupted[10] ^= 1;
+        assert_eq!(kind(&corrupted, &[]), io::ErrorKind::InvalidData);
+        // The checksum covers the header too, so a document is not given to the wrong decoder
+        let mut changed = serialize_document(&PrefixEncoding, FLAG_CHECKSUM, &c);
+        changed[5] = EncodingId::PrefixCompressed as u8;
+        assert_eq!(kind(&changed, &[]), io::ErrorKind::InvalidData);
+        // Without a checksum, a corrupt tree is only found when it is decoded
+        let unchecked = serialize_document(&PrefixCompressedEncoding, 0, &c);
+        let document = open(&unchecked[..unchecked.len() - 1], &[]).unwrap();
+        let mut out = Count(0);
+        let error = document.try_visit(&mut out).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(out.0, 0);
+        let mut out = Count(0);
+        open(&data, &[]).unwrap().try_visit(&mut out).unwrap();
+        assert_eq!(out.0, 10);
+        // Documents from later versions, or using encodings or features this version does not have, are detected
+        for (position, value) in [(4, FORMAT_VERSION + 1), (5, 100), (6, 2)] {
+            let mut changed = data.clone();
+            changed[position] = value;
+            assert_eq!(kind(&changed, &[]), io::ErrorKind::Unsupported);
+        }
+        // Dictionary documents need their dictionary
+        let data = serialize_document(&e, 0, &c);
+        assert_eq!(kind(&data, &[]), io::ErrorKind::NotFound);
+        let other = Rc::new(TemplateDictionary::from_schemas(&[Color::schema()]));
+        assert_eq!(kind(&data, &[other]), io::ErrorKind::NotFound);
+    }
+
+    /// Accepts a limited number of bytes, then fails.
+    struct LimitedWriter(usize);
+
+    impl Write for LimitedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.len() > self.0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "limit reached"));
+            }
+            self.0 -= buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn check_write<T: Encoder<Value = Vec<u8>>>(c: &Concrete<Vec<u8>>, e: T) {
+        let encoded = e.serialize(c);
+
+        let mut streamed = io::Cursor::new(vec![]);
+        e.write(c, &mut streamed).unwrap();
+        assert_eq!(streamed.into_inner(), encoded);
+
+        let mut buffer = vec![9; 1000];
+        let capacity = buffer.capacity();
+        e.serialize_into(c, &mut buffer);
+        assert_eq!(buffer, encoded);
+        assert_eq!(buffer.capacity(), capacity);
+
+        e.write(c, &mut LimitedWriter(encoded.len())).unwrap();
+        let error = e
+            .write(c, &mut LimitedWriter(encoded.len() - 1))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn write_streaming() {
+        let c = Concrete::List(vec![
+            Concrete::List(vec![Concrete::Value(vec![12])]),
+            Concrete::List(vec![Concrete::Value(vec![12]), Concrete::Value(vec![13])]),
+            Concrete::List(vec![Concrete::Value(vec![12])]),
+        ]);
+        check_write(&c, BasicEncoding);
+        check_write(&c, PrefixEncoding);
+        check_write(&c, PrefixCompressedEncoding);
+        check_write(&c, PrefixSizedEncoding);
+    }
+
+    #[test]
+    fn sized_random_access() {
+        let c = Concrete::List(vec![
+            Concrete::List(vec![Concrete::Value(vec![12]), Concrete::Value(vec![13])]),
+            Concrete::List((0..200).map(|i| Concrete::Value(vec![i as u8])).collect()),
+            Concrete::List(vec![Concrete::Value(vec![14])]),
+        ]);
+        let mut encoded = PrefixSizedEncoding.serialize(&c);
+
+        // Corrupt the content of the large middle list: accessing its siblings should not read it.
+        let middle = match &c {
+            Concrete::List(children) => PrefixSizedEncoding.serialize(&children[1]),
+            Concrete::Value(_) => unreachable!(),
+        };
+        let start = encoded
+            .windows(middle.len())
+            .position(|w| w == &middle[..])
+            .unwrap();
+        let content: usize = (0..200)
+            .map(|i| {
+                PrefixSizedEncoding
+                    .serialize(&Concrete::Value(vec![i as u8]))
+                    .len()
+            })
+            .sum();
+        let content_start = start + middle.len() - content;
+        for b in &mut encoded[content_start..content_start + content] {
+            *b = 3;
+        }
+
+        let root = SizedNode::new(&encoded).unwrap();
+        assert_eq!(root.children().count(), 3);
+        assert_eq!(root.child(1).unwrap().children().len(), 200);
+        let last = root.child(2).unwrap();
+        assert_eq!(
+            view_to_concrete(&last),
+            Concrete::List(vec![Concrete::Value(vec![14])])
+        );
+        assert_eq!(last.child(0).unwrap().value(), Some(&[14u8][..]));
+        assert!(root.child(3).is_none());
+    }
+
+    #[test]
+    fn sized_invalid_size() {
+        let mut encoded =
+            PrefixSizedEncoding.serialize(&Concrete::List(vec![Concrete::Value(vec![12])]));
+        // The root's size follows its one byte list marker.
+        for size in &[u64::MAX, encoded.len() as u64] {
+            encoded[1..9].copy_from_slice(&size.to_le_bytes());
+            let error = SizedNode::new(&encoded).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        assert_eq!(
+            SizedNode::new(&[]).err().unwrap().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    fn basic_decode(data: Vec<u8>) -> Concrete<Vec<u8>> {
+        view_to_concrete(&EncodedLeafTree {
+            decoder: BasicEncoding,
+            data,
+        })
+    }
+
+    #[test]
+    #[should_panic(expected = "Value at 3 must be the only content of its node")]
+    fn basic_decode_value_in_list() {
+        basic_decode(vec![0, 0, 2, 1, 12, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected data at 2")]
+    fn basic_decode_trailing_data() {
+        basic_decode(vec![1, 12, 1, 13]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected LIST_END at 3")]
+    fn basic_decode_truncated() {
+        basic_decode(vec![0, 1, 12]);
+    }
 }
 
 mod test_data {
     use super::data_models::typed_value_tree::{MapView, MapVisitor, TypeView, TypeVisitor};
     use super::into_typed_value_tree::{
-        visit_list_field, visit_single_field, Named, Struct, Terminal,
+        single_field_schema, visit_list_field, visit_single_field, FieldSchema, HasSchema, Named,
+        PlainData, Schema, Struct, Terminal,
     };
     use serde_derive::{Deserialize, Serialize};
+    use std::mem::offset_of;
 
     #[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
     pub struct TestData {
@@ -179,7 +1584,8 @@ mod test_data {
         pub colors: Vec<Color>,
     }
 
-    #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+    #[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
+    #[repr(C)]
     pub struct Color {
         #[serde(rename = "436ff18bf3f14263856343a575edd1c6")]
         pub r: u8,
@@ -202,6 +1608,15 @@ mod test_data {
         }
     }
 
+    impl HasSchema for u8 {
+        fn schema() -> Schema {
+            Schema::Terminal {
+                id: <u8 as Terminal>::get_id(),
+                size: Some(1),
+            }
+        }
+    }
+
     TypeViewForStruct!(TestData);
     impl Struct for TestData {
         fn get_id() -> u128 {
@@ -213,6 +1628,15 @@ mod test_data {
         }
     }
 
+    impl HasSchema for TestData {
+        fn schema() -> Schema {
+            Schema::Struct {
+                id: <Self as Struct>::get_id(),
+                fields: vec![FieldSchema::List { name: 1234 }],
+            }
+        }
+    }
+
     TypeViewForStruct!(Color);
     impl Struct for Color {
         fn get_id() -> u128 {
@@ -226,4 +1650,29 @@ mod test_data {
             visit_single_field(v, &1231354u128, &self.a);
         }
     }
+
+    impl HasSchema for Color {
+        fn schema() -> Schema {
+            Schema::Struct {
+                id: <Self as Struct>::get_id(),
+                fields: vec![
+                    single_field_schema::<u8>(1255454),
+                    single_field_schema::<u8>(1215334),
+                    single_field_schema::<u8>(1213534),
+                    single_field_schema::<u8>(1231354),
+                ],
+            }
+        }
+    }
+
+    unsafe impl PlainData for Color {
+        fn terminal_offsets() -> Vec<usize> {
+            vec![
+                offset_of!(Color, r),
+                offset_of!(Color, g),
+                offset_of!(Color, b),
+                offset_of!(Color, a),
+            ]
+        }
+    }
 }
@@ -1,12 +1,21 @@
 //! Each node is encoded as a type indicator (list or value), then the actual data.
-//! 
+//!
 //! Lists are prefixed with their count.
-//! 
+//! PrefixSizedEncoding also records the size in bytes of each list's children after the count,
+//! which allows skipping subtrees without parsing them (see SizedNode).
+//!
 //! Values are written directly.
-//! 
+//!
+//! Integers in the format (list counts, value lengths, template indexes, repeat counts, strides and offsets)
+//! are varints (see varint), except for PrefixSizedEncoding's sizes which are 8 bytes so they can be patched in place.
+//! This is version 2 of the format: version 1 used fixed size little endian integers.
+//! The encodings' output does not record its version (or its encoding): documents written by container do.
+//!
 //! Other contend type id's are used for more compact optional optimizations, including:
 //! - Template Tree: generates a tree (template ref + data stream)
 //! - Template Sequence: generates multiple siblings (template ref + data stream)
+//! - Template Columns: generates multiple siblings from a byte pattern (template ref + one data stream per value in the pattern)
+//! - Template Patch: generates a previous subtree with some of its values replaced (template ref + replacements)
 //!
 //! A template ref can either define a template inline,
 //! or reference a previous template (for now by index out of all templates).
@@ -17,148 +26,944 @@
 //! Generators may reference the data stream and/or move the stream pointer (out of order access is allowed).
 //! This gives the encoder control of the memory layout trees when desired which can enable fast path encoders and decoders for particular templates.
 //!
-// TODO: we could allow 0+ data streams enabling arrays of structure -> structure of arrays encoding
+//! Runs of siblings using the same byte pattern are written with their data as a structure of arrays (Template Columns),
+//! so each value in the pattern (for example each field of a struct) has a stream of its own.
+//! Similar values end up next to each other, which helps general purpose compression and columnar access.
+//!
+//! PrefixDictionaryEncoding documents start with the id of a TemplateDictionary (8 bytes, little endian),
+//! then continue as if they followed the dictionary's document: they can use its templates without defining them.
+//!
+//! PrefixWindowedEncoding documents start with the size of their template window (as a varint):
+//! when a template is added beyond it, the oldest one is evicted, and any later use has to define it again.
 
+/// Version of the wire format written and read by the encodings in this module.
+///
+/// The encodings neither write nor check it: only documents written by container record it in their header,
+/// and open rejects any other version. Bare encoded data is only readable by a build using the same version.
+pub const FORMAT_VERSION: u8 = 2;
+
+#[derive(Clone)]
 pub struct PrefixEncoding;
+#[derive(Clone)]
 pub struct PrefixCompressedEncoding;
+#[derive(Clone)]
+pub struct PrefixSizedEncoding;
+
+/// Encodes values of T with a template derived once from T's schema (see type_to_leaf::schema_tree_template),
+/// so encoding a value only writes its data stream, straight from its TypeView: nothing is interned or searched for redundancy.
+/// The output is decoded by PrefixCompressedEncoding.
+pub struct SchemaEncoding<T> {
+    schema: Schema,
+    template: SchemaTemplate,
+    phantom: std::marker::PhantomData<T>,
+}
+
+enum SchemaTemplate {
+    BytePattern(BytePatternTemplate),
+    Tree(TreeTemplate),
+}
+
+/// Templates learned from sample documents, which PrefixDictionaryEncoding documents can use without defining them
+/// (like zstd dictionaries), so small documents with common shapes do not each pay to define their templates.
+///
+/// A dictionary is saved as a PrefixCompressedEncoding document (see TemplateDictionary::train),
+/// and identified by a fingerprint of that document.
+/// Alternatively it can be built from schema types (see TemplateDictionary::from_schemas), so nothing needs to be saved.
+pub struct TemplateDictionary {
+    id: u64,
+    /// The state after encoding the dictionary's document, which each document's state continues from.
+    encoder_state: Rc<State>,
+    /// The state after decoding the dictionary's document, which each document's state continues from.
+    decoder_state: Rc<State>,
+}
+
+/// PrefixCompressedEncoding, continuing from a TemplateDictionary.
+#[derive(Clone)]
+pub struct PrefixDictionaryEncoding {
+    pub dictionary: Rc<TemplateDictionary>,
+    /// The encoder's choices, as for PrefixTunedEncoding.
+    pub options: CompressionOptions,
+}
+
+/// PrefixCompressedEncoding, keeping only the latest max_templates templates, so the template table does not grow without limit
+/// (for example when encoding a long log). The tree itself is still held in memory while encoding or decoding.
+///
+/// The window is written at the start of each document, so it decodes the same as PrefixWindowedDecoding,
+/// whatever this max_templates is.
+#[derive(Clone)]
+pub struct PrefixWindowedEncoding {
+    pub max_templates: NonZeroU32,
+    /// The encoder's choices, as for PrefixTunedEncoding.
+    pub options: CompressionOptions,
+}
+
+/// Decodes PrefixWindowedEncoding documents with any window, as each one starts with its own.
+#[derive(Clone)]
+pub struct PrefixWindowedDecoding;
+
+/// PrefixCompressedEncoding, with the encoder's choices set by options. Decoding is the same as PrefixCompressedEncoding.
+#[derive(Clone, Default)]
+pub struct PrefixTunedEncoding {
+    pub options: CompressionOptions,
+}
+
+/// Which optimizations the PrefixCompressedEncoding encoder uses, and when.
+/// These are only choices of the encoder (the decoder follows whatever the document contains),
+/// so documents written with any options are decoded the same way.
+#[derive(Clone, Debug)]
+pub struct CompressionOptions {
+    /// Each template use must be at least this many bytes smaller than what it replaces, as estimated by the encoder.
+    /// 1 uses templates whenever they save bytes: larger values trade size for fewer indirections when decoding.
+    pub min_savings: usize,
+    /// Write repeated subtrees as uses of the first one.
+    pub subtree_templates: bool,
+    /// Write subtrees with the same shape as uses of byte pattern templates.
+    pub byte_patterns: bool,
+    /// Write lists with the same head as uses of tree templates.
+    pub tree_templates: bool,
+    /// Write subtrees which differ from a previous one in a few values as patched uses of it.
+    pub patches: bool,
+    /// Merge runs of siblings using the same template into a TEMPLATE_USE_SEQUENCE.
+    pub sequences: bool,
+    /// Write sequences of byte pattern uses as columns (TEMPLATE_USE_COLUMNS).
+    pub columns: bool,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> CompressionOptions {
+        CompressionOptions {
+            min_savings: 1,
+            subtree_templates: true,
+            byte_patterns: true,
+            tree_templates: true,
+            patches: true,
+            sequences: true,
+            columns: true,
+        }
+    }
+}
 
-use super::data_models::leaf_tree::concrete::{view_to_concrete, Concrete};
+use super::data_models::leaf_tree::concrete::Concrete;
 use super::data_models::leaf_tree::{View, Visitor};
-use super::encoding::{Decoder, Encoder};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::collections::HashMap;
-use std::io::Cursor;
+use super::data_models::typed_value_tree::TypeView;
+use super::encoding::{Decoder, Encoder, Event, IncrementalDecoder};
+use super::into_typed_value_tree::{FieldSchema, HasSchema, PlainData, Schema};
+use super::leaf_tree_template::{
+    BytePatternChild, BytePatternTemplate, BytePatternView, OffsetTemplateUse, TreeTemplate,
+};
+use super::type_to_leaf::{
+    schema_byte_pattern, schema_tree_template, write_schema_byte_pattern_data,
+    write_schema_tree_stream,
+};
+use super::varint::{read_varint, read_varint_u32, varint_size, write_varint};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Cursor, Read, Write};
+use std::num::NonZeroU32;
+use std::rc::Rc;
 
 impl Encoder for PrefixEncoding {
-    type Value = u8;
-    fn serialize<TView: View<Value = Self::Value>>(&self, t: &TView) -> Vec<u8> {
-        let c = view_to_concrete(t);
-        let mut out = vec![];
-        prefix_encode(&c, &mut out);
-        return out;
+    type Value = Vec<u8>;
+    fn write<TView: View<Value = Self::Value>, W: Write>(
+        &self,
+        t: &TView,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let mut output = PrefixOutput {
+            out,
+            result: Ok(()),
+        };
+        output.write_node(t);
+        output.result
     }
 }
 
 impl Decoder for PrefixEncoding {
-    type Value = u8;
+    type Value = Vec<u8>;
     fn visit_root<V: Visitor<Value = Self::Value>>(&self, data: &[u8], v: &mut V) {
         let mut rdr = Cursor::new(data);
         prefix_decode(&mut rdr).visit(v);
     }
 }
 
+/// Progress through a PrefixEncoding document, for IncrementalDecoder.
+#[derive(Default)]
+pub struct PrefixDecodeState {
+    /// Number of children not yet started for each open node, starting at the root.
+    /// Value nodes have no children.
+    path: Vec<usize>,
+    /// The root marker has been read.
+    started: bool,
+    /// The next bytes are the marker for the last started node.
+    at_node_start: bool,
+}
+
+impl IncrementalDecoder for PrefixEncoding {
+    type Value = Vec<u8>;
+    type State = PrefixDecodeState;
+
+    fn next_event(
+        &self,
+        state: &mut PrefixDecodeState,
+        input: &[u8],
+    ) -> io::Result<(Option<Event<Vec<u8>>>, usize)> {
+        let mut consumed = 0;
+        if !state.started || state.at_node_start {
+            let (marker, size) = match peek_marker(input)? {
+                Some(marker) => marker,
+                None => return Ok((None, 0)),
+            };
+            consumed = size;
+            state.started = true;
+            state.at_node_start = false;
+            match marker {
+                Marker::List(count) => state.path.push(count),
+                Marker::Value(value) => {
+                    state.path.push(0);
+                    return Ok((Some(Event::Value(value)), consumed));
+                }
+                Marker::Other(marker) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid marker {}", marker),
+                    ))
+                }
+            }
+        }
+
+        match state.path.last_mut() {
+            None => {
+                if input.len() > consumed {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unexpected data after root",
+                    ));
+                }
+                Ok((None, consumed))
+            }
+            Some(0) => {
+                state.path.pop();
+                if state.path.is_empty() {
+                    // The root is implicit, so has no events
+                    Ok((None, consumed))
+                } else {
+                    Ok((Some(Event::ListEnd), consumed))
+                }
+            }
+            Some(remaining) => {
+                *remaining -= 1;
+                state.at_node_start = true;
+                Ok((Some(Event::ListStart), consumed))
+            }
+        }
+    }
+
+    fn can_end(&self, state: &PrefixDecodeState) -> bool {
+        state.started && state.path.is_empty()
+    }
+}
+
 impl Encoder for PrefixCompressedEncoding {
-    type Value = u8;
-    fn serialize<TView: View<Value = Self::Value>>(&self, t: &TView) -> Vec<u8> {
-        let c = view_to_concrete(t);
-        let mut out = vec![];
-        prefix_encode_compressed(&mut State::new(), &c, &mut out);
-        return out;
+    type Value = Vec<u8>;
+    fn write<TView: View<Value = Self::Value>, W: Write>(
+        &self,
+        t: &TView,
+        out: &mut W,
+    ) -> io::Result<()> {
+        PrefixTunedEncoding::default().write(t, out)
+    }
+}
+
+impl Encoder for PrefixTunedEncoding {
+    type Value = Vec<u8>;
+    fn write<TView: View<Value = Self::Value>, W: Write>(
+        &self,
+        t: &TView,
+        out: &mut W,
+    ) -> io::Result<()> {
+        // The whole tree is interned first, so how to write each node can be decided before writing it,
+        // and the output streamed (see Run for the only part which is held back).
+        let mut state = State::new();
+        let root = state.intern_view(t);
+        CompressedOutput {
+            state: &mut state,
+            out,
+            options: &self.options,
+        }
+        .write_node(root)
+    }
+}
+
+impl Decoder for PrefixTunedEncoding {
+    type Value = Vec<u8>;
+    fn visit_root<V: Visitor<Value = Self::Value>>(&self, data: &[u8], v: &mut V) {
+        PrefixCompressedEncoding.visit_root(data, v)
+    }
+}
+
+impl PrefixCompressedEncoding {
+    /// Decoder::visit_root, failing if data is malformed or truncated. Nothing is visited if it fails.
+    pub fn try_visit_root<V: Visitor<Value = Vec<u8>>>(
+        &self,
+        data: &[u8],
+        v: &mut V,
+    ) -> io::Result<()> {
+        let mut rdr = Cursor::new(data);
+        let mut state = State::new();
+        let root = decode_compressed_document(&mut state, &mut rdr)?;
+        NodeView {
+            state: &state,
+            id: root,
+        }
+        .visit(v);
+        Ok(())
     }
 }
 
 impl Decoder for PrefixCompressedEncoding {
-    type Value = u8;
+    type Value = Vec<u8>;
+    fn visit_root<V: Visitor<Value = Self::Value>>(&self, data: &[u8], v: &mut V) {
+        if let Err(error) = self.try_visit_root(data, v) {
+            panic!("{}", error);
+        }
+    }
+}
+
+impl TemplateDictionary {
+    /// Writes a dictionary document for samples: a list of them, which defines the templates they have in common.
+    pub fn train<T: View<Value = Vec<u8>>>(samples: &[T]) -> Vec<u8> {
+        PrefixCompressedEncoding.serialize(&Samples(samples))
+    }
+
+    /// Loads a dictionary document.
+    /// Any PrefixCompressedEncoding document can be used, as long as it is how PrefixCompressedEncoding encodes its tree
+    /// (so encoding it gives the same templates as decoding it): otherwise this fails with io::ErrorKind::InvalidData.
+    pub fn load(data: &[u8]) -> io::Result<TemplateDictionary> {
+        let mut rdr = Cursor::new(data);
+        let mut decoder_state = State::new();
+        let root = decode_compressed_document(&mut decoder_state, &mut rdr)?;
+        if rdr.position() as usize != data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected data after dictionary",
+            ));
+        }
+
+        let mut encoder_state = State::new();
+        let root = encoder_state.intern_view(&NodeView {
+            state: &decoder_state,
+            id: root,
+        });
+        let mut encoded = vec![];
+        CompressedOutput {
+            state: &mut encoder_state,
+            out: &mut encoded,
+            options: &CompressionOptions::default(),
+        }
+        .write_node(root)?;
+        if encoded != data {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Dictionary is not encoded as PrefixCompressedEncoding would encode it",
+            ));
+        }
+
+        Ok(TemplateDictionary {
+            id: fingerprint(data),
+            encoder_state: Rc::new(encoder_state),
+            decoder_state: Rc::new(decoder_state),
+        })
+    }
+
+    /// A dictionary with templates for values of the given schemas (and the schemas of their fields),
+    /// so their type ids, field names and shapes are numbered ahead of time and never written in documents.
+    /// List fields have no schema, so the schemas of their items should be included too.
+    ///
+    /// Each type's id and field names are recorded values, followed by its byte pattern template if all its values have the same size,
+    /// otherwise its tree template (for Structs).
+    /// Both states are built directly from the schemas. The id is a fingerprint of the template definitions,
+    /// so a reader with different schemas can detect that (see TemplateDictionary::is_used_by) and fall back.
+    pub fn from_schemas(schemas: &[Schema]) -> TemplateDictionary {
+        let mut state = State::new();
+        let mut definitions = vec![];
+        let mut seeded = HashSet::new();
+        for schema in schemas {
+            seed_schema(&mut state, schema, &mut seeded, &mut definitions);
+        }
+        let state = Rc::new(state);
+        TemplateDictionary {
+            id: fingerprint(&definitions),
+            encoder_state: state.clone(),
+            decoder_state: state,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// If a PrefixDictionaryEncoding document was written with this dictionary, so it can be decoded with it.
+    pub fn is_used_by(&self, document: &[u8]) -> bool {
+        document.len() >= 8 && LittleEndian::read_u64(document) == self.id
+    }
+}
+
+/// Adds the templates for a schema to state (after those of its fields' schemas, each type only once),
+/// and writes their definitions to out.
+fn seed_schema(state: &mut State, schema: &Schema, seeded: &mut HashSet<u128>, out: &mut Vec<u8>) {
+    let id = match schema {
+        Schema::Terminal { id, .. } | Schema::Struct { id, .. } => *id,
+    };
+    if !seeded.insert(id) {
+        return;
+    }
+    let mut names = vec![id];
+    if let Schema::Struct { fields, .. } = schema {
+        for field in fields {
+            match field {
+                FieldSchema::Single { name, schema } => {
+                    seed_schema(state, schema, seeded, out);
+                    names.push(*name);
+                }
+                FieldSchema::List { name } => names.push(*name),
+            }
+        }
+    }
+    for name in names {
+        let value = name.to_le_bytes().to_vec();
+        write_value(out, &value).unwrap();
+        let node = state.intern(Node::Value(value));
+        state.record(node);
+    }
+
+    match (schema_byte_pattern(schema), schema) {
+        (Some(pattern), _) if pattern.size > 0 => {
+            out.push(BYTE_PATTERN_TEMPLATE_MARKER);
+            write_varint(out, pattern.size as u64).unwrap();
+            write_byte_pattern(state, out, &pattern.content).unwrap();
+            let zeros = vec![0; pattern.size as usize];
+            let instance = state.intern_view(&BytePatternView::new(&pattern, &zeros));
+            let shape = state.info(instance).shape;
+            let pattern = Rc::new(pattern);
+            state.shapes.insert(
+                shape,
+                ShapeState {
+                    first: None,
+                    pattern: Some(pattern.clone()),
+                },
+            );
+            state.add_byte_pattern(pattern);
+        }
+        (_, Schema::Struct { fields, .. }) => {
+            let template = schema_tree_template(schema);
+            out.push(TREE_TEMPLATE_MARKER);
+            write_tree_template(state, out, &template, true).unwrap();
+            let head = vec![
+                TreeHead::Value(id.to_le_bytes().to_vec()),
+                TreeHead::List(fields.len() * 2),
+            ];
+            let template = Rc::new(template);
+            state.tree_shapes.insert(
+                head,
+                TreeShapeUse {
+                    first: None,
+                    template: Some(template.clone()),
+                },
+            );
+            state.add_tree_template(template);
+        }
+        // Values of variable size terminals have nothing in common but their type id.
+        (_, Schema::Terminal { .. }) => {}
+    }
+}
+
+/// View of a list of samples.
+struct Samples<'a, T>(&'a [T]);
+
+impl<'a, T: View<Value = Vec<u8>>> View for Samples<'a, T> {
+    type Value = Vec<u8>;
+    fn visit<V: Visitor<Value = Vec<u8>>>(&self, v: &mut V) {
+        for sample in self.0 {
+            v.visit_list(sample);
+        }
+    }
+}
+
+/// 64 bit FNV-1a hash, used to identify dictionaries (and to check documents, see container).
+pub fn fingerprint(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl Encoder for PrefixDictionaryEncoding {
+    type Value = Vec<u8>;
+    fn write<TView: View<Value = Self::Value>, W: Write>(
+        &self,
+        t: &TView,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let mut state = State::with_base(self.dictionary.encoder_state.clone());
+        let root = state.intern_view(t);
+        out.write_u64::<LittleEndian>(self.dictionary.id)?;
+        CompressedOutput {
+            state: &mut state,
+            out,
+            options: &self.options,
+        }
+        .write_node(root)
+    }
+}
+
+impl PrefixDictionaryEncoding {
+    /// Decoder::visit_root, failing with io::ErrorKind::NotFound if the document was written with a different dictionary
+    /// (see TemplateDictionary::is_used_by), or io::ErrorKind::InvalidData if it is malformed. Nothing is visited if it fails.
+    pub fn try_visit_root<V: Visitor<Value = Vec<u8>>>(
+        &self,
+        data: &[u8],
+        v: &mut V,
+    ) -> io::Result<()> {
+        let mut rdr = Cursor::new(data);
+        let id = rdr.read_u64::<LittleEndian>()?;
+        if id != self.dictionary.id {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Document uses a different dictionary",
+            ));
+        }
+        let mut state = State::with_base(self.dictionary.decoder_state.clone());
+        let root = decode_compressed_document(&mut state, &mut rdr)?;
+        NodeView {
+            state: &state,
+            id: root,
+        }
+        .visit(v);
+        Ok(())
+    }
+}
+
+impl Decoder for PrefixDictionaryEncoding {
+    type Value = Vec<u8>;
     fn visit_root<V: Visitor<Value = Self::Value>>(&self, data: &[u8], v: &mut V) {
+        if let Err(error) = self.try_visit_root(data, v) {
+            panic!("{}", error);
+        }
+    }
+}
+
+impl Encoder for PrefixWindowedEncoding {
+    type Value = Vec<u8>;
+    fn write<TView: View<Value = Self::Value>, W: Write>(
+        &self,
+        t: &TView,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let mut state = State::with_max_templates(self.max_templates);
+        let root = state.intern_view(t);
+        write_varint(out, self.max_templates.get() as u64)?;
+        CompressedOutput {
+            state: &mut state,
+            out,
+            options: &self.options,
+        }
+        .write_node(root)
+    }
+}
+
+impl Decoder for PrefixWindowedEncoding {
+    type Value = Vec<u8>;
+    fn visit_root<V: Visitor<Value = Self::Value>>(&self, data: &[u8], v: &mut V) {
+        PrefixWindowedDecoding.visit_root(data, v)
+    }
+}
+
+impl PrefixWindowedDecoding {
+    /// Decoder::visit_root, failing with io::ErrorKind::InvalidData if the document is malformed
+    /// (including an empty window). Nothing is visited if it fails.
+    pub fn try_visit_root<V: Visitor<Value = Vec<u8>>>(
+        &self,
+        data: &[u8],
+        v: &mut V,
+    ) -> io::Result<()> {
         let mut rdr = Cursor::new(data);
-        prefix_decode_compressed(&mut State::new(), &mut rdr).visit(v);
+        let max_templates = NonZeroU32::new(read_varint_u32(&mut rdr)?)
+            .ok_or_else(|| invalid_data("The template window must not be empty".to_string()))?;
+        let mut state = State::with_max_templates(max_templates);
+        let root = decode_compressed_document(&mut state, &mut rdr)?;
+        NodeView {
+            state: &state,
+            id: root,
+        }
+        .visit(v);
+        Ok(())
+    }
+}
+
+impl Decoder for PrefixWindowedDecoding {
+    type Value = Vec<u8>;
+    fn visit_root<V: Visitor<Value = Self::Value>>(&self, data: &[u8], v: &mut V) {
+        if let Err(error) = self.try_visit_root(data, v) {
+            panic!("{}", error);
+        }
+    }
+}
+
+impl Encoder for PrefixSizedEncoding {
+    type Value = Vec<u8>;
+    fn write<TView: View<Value = Self::Value>, W: Write>(
+        &self,
+        t: &TView,
+        out: &mut W,
+    ) -> io::Result<()> {
+        // Sizes are only known once the children have been written, so output is buffered to allow patching them.
+        let mut buffer = vec![];
+        SizedOutput { out: &mut buffer }.write_node(t);
+        out.write_all(&buffer)
+    }
+}
+
+impl Decoder for PrefixSizedEncoding {
+    type Value = Vec<u8>;
+    fn visit_root<V: Visitor<Value = Self::Value>>(&self, data: &[u8], v: &mut V) {
+        SizedNode::new(data).unwrap().visit(v);
+    }
+}
+
+impl<T: HasSchema + TypeView<N = u128>> SchemaEncoding<T> {
+    /// Uses a byte pattern template if all values of T have the same size, otherwise a tree template.
+    pub fn new() -> SchemaEncoding<T> {
+        let schema = T::schema();
+        let template = match schema_byte_pattern(&schema) {
+            Some(pattern) => SchemaTemplate::BytePattern(pattern),
+            None => SchemaTemplate::Tree(schema_tree_template(&schema)),
+        };
+        SchemaEncoding {
+            schema,
+            template,
+            phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// Writes a document holding value: the template's definition, then value's data stream.
+    pub fn write<W: Write>(&self, value: &T, out: &mut W) -> io::Result<()> {
+        self.write_definition(value, out)
+    }
+
+    /// Writes a document holding a list of values:
+    /// the first defines the template, and the rest are a TEMPLATE_USE_SEQUENCE (or TEMPLATE_USE_COLUMNS) of it.
+    pub fn write_list<W: Write>(&self, values: &[T], out: &mut W) -> io::Result<()> {
+        write_list_marker(out, values.len())?;
+        if let Some((first, rest)) = values.split_first() {
+            self.write_definition(first, out)?;
+            if !rest.is_empty() {
+                // The definition is the first template in the document, so it has index 0.
+                self.write_uses(rest, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_definition<W: Write>(&self, value: &T, out: &mut W) -> io::Result<()> {
+        let state = State::new();
+        match &self.template {
+            SchemaTemplate::BytePattern(pattern) => {
+                out.write_u8(BYTE_PATTERN_TEMPLATE_MARKER)?;
+                write_varint(out, pattern.size as u64)?;
+                write_byte_pattern(&state, out, &pattern.content)?;
+                write_schema_byte_pattern_data(&self.schema, value, out)
+            }
+            SchemaTemplate::Tree(template) => {
+                out.write_u8(TREE_TEMPLATE_MARKER)?;
+                write_tree_template(&state, out, template, true)?;
+                write_schema_tree_stream(&self.schema, value, out)
+            }
+        }
+    }
+
+    fn write_uses<W: Write>(&self, values: &[T], out: &mut W) -> io::Result<()> {
+        let mut slots = vec![];
+        if let SchemaTemplate::BytePattern(pattern) = &self.template {
+            stream_slots(&pattern.content, 0, &mut slots);
+        }
+        out.write_u8(if slots.len() > 1 {
+            TEMPLATE_USE_COLUMNS_MARKER
+        } else {
+            TEMPLATE_USE_SEQUENCE_MARKER
+        })?;
+        write_varint(out, 0)?;
+        write_varint(out, values.len() as u64)?;
+        if slots.len() > 1 {
+            // Each column holds a value from every row, so the rows are needed before any column can be written.
+            let mut rows = vec![];
+            for value in values {
+                let mut row = vec![];
+                write_schema_byte_pattern_data(&self.schema, value, &mut row)?;
+                rows.push(row);
+            }
+            for (offset, length) in slots {
+                for row in &rows {
+                    out.write_all(&row[offset as usize..(offset + length) as usize])?;
+                }
+            }
+        } else {
+            for value in values {
+                match &self.template {
+                    SchemaTemplate::BytePattern(_) => {
+                        write_schema_byte_pattern_data(&self.schema, value, out)?
+                    }
+                    SchemaTemplate::Tree(_) => write_schema_tree_stream(&self.schema, value, out)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: HasSchema + TypeView<N = u128>> Default for SchemaEncoding<T> {
+    fn default() -> SchemaEncoding<T> {
+        SchemaEncoding::new()
     }
 }
 
 const LIST_MARKER: u8 = 0;
-// list with length in next 8 bytes
+// list with length in next varint
 
 const VALUE_MARKER: u8 = 1;
-// value is next byte
+// value of exactly one byte, which is next
+
+const BYTES_MARKER: u8 = 6;
+// value with length in next varint, then the bytes
+
+const INLINE_BYTES_MIN: u8 = 64;
+// 64-127 = value, inline length (subtract 64 from this byte), then the bytes
 
 const INLINE_LIST_MIN: u8 = 128;
 // 128-255 = list, inline length (subtract 128 from this byte)
 
 // Markers used in compressed versions:
 
-// Appends a new template
+// Appends a new template, and uses it for this node (as TEMPLATE_USE_MARKER)
 const BYTE_PATTERN_TEMPLATE_MARKER: u8 = 2;
-// u32 size (length in stream)
-// u8:
+// varint size (length in stream)
+// pattern, a tree where each node starts with u8:
 //      0 | 128-255 = list (length + children)
-//      1 = constant value, next byte
-//      2 = value from stream, followed by offset next 4 bytes
+//      1 | 6 | 64-127 = constant value (as for values above)
+//      2 = value from stream, followed by varint offset and varint length
 //      3 = invalid
-//      4 = TEMPLATE_USE (as below, except followed by offset istead of data stream)
+//      4 = TEMPLATE_USE of a BYTE_PATTERN_TEMPLATE (as below, except followed by varint offset istead of data stream)
 //      5 = TEMPLATE_USE_SEQUENCE (as below, except followed by offset istead of data stream)
-//      6-127 = reserved for future use
-//
+//      7-63 = reserved for future use
+// data stream (length = size)
+
+const STREAM_VALUE_MARKER: u8 = 2;
 
-// Appends a new template
+// Appends a new template, and uses it for this node (as TEMPLATE_USE_MARKER)
 // Note: the above templates populate tree values from bytes in a byte stream.
 // a higher level more flexible approach (this one) would swap subtrees from a tree stream into the template to allow applying templates for trees where some subtrees are sometimes not the same shape.
 const TREE_TEMPLATE_MARKER: u8 = 3;
-// Template format:
-// u8:
-//      0 = list,
-//      1 = constant value, next byte
+// Template format, a tree where each node starts with u8:
+//      0 | 128-255 = list (length + children)
+//      1 | 6 | 64-127 = constant value (as for values above)
 //      2 = value from stream
 //      3 = tree from stream
 //      4 = TEMPLATE_USE (as below, except not followed by data stream)
 //      5 = TEMPLATE_USE_SEQUENCE (as below, except not followed by data stream)
-//      6-127 = reserved for future use
-//      128-255 = list, inline length (subtract 128 from this byte)
+//      7-63 = reserved for future use
+// tree stream: a node (as for any node) filling each hole in pre-order.
+// TEMPLATE_USEs of byte pattern templates within the template take their data stream from the tree stream at their position.
+
+const STREAM_TREE_MARKER: u8 = 3;
 
 const TEMPLATE_USE_MARKER: u8 = 4;
-// u32: template index (TODO: varalible length encoding?)
-// data stream (if BYTE_PATTERN_TEMPLATE: length = template' stride)
+// varint: template index
+// data stream (if BYTE_PATTERN_TEMPLATE: length = template' stride, if TREE_TEMPLATE: tree stream)
 
-// Multiple nodes in a row in the same list using the same template
+// Multiple nodes in a row in the same list using the same template (only valid as children of a list)
 const TEMPLATE_USE_SEQUENCE_MARKER: u8 = 5;
-// u32: template index (TODO: varalible length encoding?)
-// u32: repeate count (TODO: varalible length encoding?)
+// varint: template index
+// varint: repeate count
 // data stream (if BYTE_PATTERN_TEMPLATE: length = repeate count * template' stride)
 
+// Multiple nodes in a row in the same list using the same BYTE_PATTERN_TEMPLATE,
+// with their data stored as a structure of arrays instead of an array of structures (only valid as children of a list)
+const TEMPLATE_USE_COLUMNS_MARKER: u8 = 7;
+// varint: template index
+// varint: repeate count
+// data streams, one for each value from the stream in the template (in pre-order, including those in templates it uses),
+// holding that value for every use in turn (length = repeate count * value length).
+// For example a run of RGBA colors stores all the reds, then all the greens, then all the blues and then all the alphas.
+
+// Defines a BYTE_PATTERN_TEMPLATE, and uses it for multiple nodes in a row in the same list (only valid as children of a list)
+const BYTE_PATTERN_TEMPLATE_SEQUENCE_MARKER: u8 = 8;
+// varint size, pattern (as for BYTE_PATTERN_TEMPLATE)
+// varint: repeate count
+// varint: padding length, then that many bytes which are ignored (allowing the encoder to align the data stream)
+// data stream (length = repeate count * size)
+// This is used for slices of PlainData, which the data stream is the in memory representation of.
+
+// A previous subtree (recorded as a template), with some of its values replaced
+const TEMPLATE_USE_PATCHED_MARKER: u8 = 9;
+// varint: template index (of a subtree, not a BYTE_PATTERN_TEMPLATE or TREE_TEMPLATE)
+// varint: replacement count
+// replacements, in pre-order: varint number of values in the subtree skipped since the previous replacement
+// (or the start of the subtree), then the node replacing the next value.
+
 enum Marker {
     List(usize),
-    Value(u8),
+    Value(Vec<u8>),
     Other(u8),
 }
 
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
 fn read_marker<T: ReadBytesExt>(input: &mut T) -> Marker {
-    let marker = input.read_u8().unwrap();
-    if marker == LIST_MARKER {
-        let count = input.read_u64::<LittleEndian>().unwrap();
+    try_read_marker(input).unwrap()
+}
+
+fn try_read_marker<T: ReadBytesExt>(input: &mut T) -> io::Result<Marker> {
+    let marker = input.read_u8()?;
+    Ok(if marker == LIST_MARKER {
+        let count = read_varint(input)?;
         Marker::List(count as usize)
     } else if marker == VALUE_MARKER {
-        Marker::Value(input.read_u8().unwrap())
+        Marker::Value(vec![input.read_u8()?])
+    } else if marker == BYTES_MARKER {
+        let length = read_varint(input)?;
+        read_bytes(input, length as usize)?
+    } else if (INLINE_BYTES_MIN..INLINE_LIST_MIN).contains(&marker) {
+        read_bytes(input, (marker - INLINE_BYTES_MIN) as usize)?
     } else if marker >= INLINE_LIST_MIN {
         Marker::List((marker - INLINE_LIST_MIN) as usize)
     } else {
         Marker::Other(marker)
+    })
+}
+
+fn read_bytes<T: ReadBytesExt>(input: &mut T, length: usize) -> io::Result<Marker> {
+    Ok(Marker::Value(read_data(input, length)?))
+}
+
+/// Reads length bytes, only allocating as much as is actually there (so a corrupt length can not exhaust memory).
+fn read_data<T: ReadBytesExt>(input: &mut T, length: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    input.take(length as u64).read_to_end(&mut data)?;
+    if data.len() != length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Value extends past the end of the data",
+        ));
     }
+    Ok(data)
 }
 
-fn write_list_marker(out: &mut Vec<u8>, length: usize) {
-    if length <= (u8::max_value() - INLINE_LIST_MIN) as usize {
-        out.push(length as u8 + INLINE_LIST_MIN);
+/// Size of the marker written for a value of the given length.
+fn value_marker_size(length: usize) -> usize {
+    if length == 1 || length < (INLINE_LIST_MIN - INLINE_BYTES_MIN) as usize {
+        1
     } else {
-        out.push(LIST_MARKER);
-        out.write_u64::<LittleEndian>(length as u64).unwrap();
+        1 + varint_size(length as u64)
     }
 }
 
-fn prefix_encode(c: &Concrete<u8>, out: &mut Vec<u8>) {
-    match c {
-        Concrete::List(list) => {
-            write_list_marker(out, list.len());
-            for child in list {
-                prefix_encode(child, out);
-            }
+/// Size of the marker written for a list of the given length.
+fn list_marker_size(length: usize) -> usize {
+    if length <= (u8::MAX - INLINE_LIST_MIN) as usize {
+        1
+    } else {
+        1 + varint_size(length as u64)
+    }
+}
+
+fn write_value<W: Write>(out: &mut W, value: &[u8]) -> io::Result<()> {
+    if value.len() == 1 {
+        out.write_u8(VALUE_MARKER)?;
+    } else if value.len() < (INLINE_LIST_MIN - INLINE_BYTES_MIN) as usize {
+        out.write_u8(value.len() as u8 + INLINE_BYTES_MIN)?;
+    } else {
+        out.write_u8(BYTES_MARKER)?;
+        write_varint(out, value.len() as u64)?;
+    }
+    out.write_all(value)
+}
+
+/// Reads a marker from the start of input, returning it and its size, or None if input ends within it.
+fn peek_marker(input: &[u8]) -> io::Result<Option<(Marker, usize)>> {
+    let mut rdr = Cursor::new(input);
+    match try_read_marker(&mut rdr) {
+        Ok(marker) => Ok(Some((marker, rdr.position() as usize))),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_list_marker<W: Write>(out: &mut W, length: usize) -> io::Result<()> {
+    if length <= (u8::MAX - INLINE_LIST_MIN) as usize {
+        out.write_u8(length as u8 + INLINE_LIST_MIN)
+    } else {
+        out.write_u8(LIST_MARKER)?;
+        write_varint(out, length as u64)
+    }
+}
+
+/// Counts the children of a node, or finds its value.
+struct Counter {
+    count: usize,
+    value: Option<Vec<u8>>,
+}
+
+impl Visitor for Counter {
+    type Value = Vec<u8>;
+    fn visit_list<T: View<Value = Self::Value>>(&mut self, _t: &T) {
+        self.count += 1;
+    }
+    fn visit_value(&mut self, t: Self::Value) {
+        self.value = Some(t);
+    }
+}
+
+/// Writes PrefixEncoding directly from a View.
+/// Each list is visited twice: once to count its children for the list marker, then to write them.
+struct PrefixOutput<'a, W> {
+    out: &'a mut W,
+    /// The first error encountered. Once set, the rest of the tree is skipped.
+    result: io::Result<()>,
+}
+
+impl<'a, W: Write> PrefixOutput<'a, W> {
+    fn write_node<T: View<Value = Vec<u8>>>(&mut self, t: &T) {
+        if self.result.is_err() {
+            return;
         }
-        Concrete::Value(v) => {
-            out.push(VALUE_MARKER);
-            out.push(*v);
+        let counter = t.apply(Counter {
+            count: 0,
+            value: None,
+        });
+        self.result = match &counter.value {
+            Some(value) => write_value(self.out, value),
+            None => write_list_marker(self.out, counter.count),
+        };
+        if counter.value.is_none() {
+            t.visit(self);
         }
     }
 }
 
-fn prefix_decode<T: ReadBytesExt>(input: &mut T) -> Concrete<u8> {
+impl<'a, W: Write> Visitor for PrefixOutput<'a, W> {
+    type Value = Vec<u8>;
+    fn visit_list<T: View<Value = Self::Value>>(&mut self, t: &T) {
+        self.write_node(t);
+    }
+    fn visit_value(&mut self, _t: Self::Value) {
+        // Values are written by write_node, when counting
+    }
+}
+
+fn prefix_decode<T: ReadBytesExt>(input: &mut T) -> Concrete<Vec<u8>> {
     let marker = read_marker(input);
     match marker {
         Marker::List(count) => {
@@ -173,123 +978,2047 @@ fn prefix_decode<T: ReadBytesExt>(input: &mut T) -> Concrete<u8> {
     }
 }
 
-struct Shape {
-    counts: Vec<u32>, // the number of children in each node, in pre-order traversal order, where value nodes are encoded as u32.max_value
+type ShapeId = u32;
+
+/// The structure of a subtree, with children referenced by id (so shapes are interned like nodes, see NodeInfo).
+/// Values have their length in shapes: subtrees with the same shape only differ in the bytes of their values, so they can share a byte pattern.
+/// Values have no length in structures, which only record how lists are nested.
+#[derive(PartialEq, Eq, Hash, Clone)]
+enum ShapeNode {
+    List(Vec<ShapeId>),
+    Value(Option<usize>),
 }
 
-fn get_shape<TView: View<Value = u8>>(view: TView) -> Shape {
-    struct Out<'a>(&'a mut Vec<u32>, usize);
-    impl Visitor for Out<'_> {
-        type Value = u8;
-        fn visit_list<T: View<Value = u8>>(&mut self, t: &T) {
-            assert!(self.0[self.1] < u32::max_value() - 1);
-            self.0[self.1] = self.0[self.1] + 1;
-            self.0.push(0);
-            let mut out_nested = Out(self.0, self.0.len() - 1);
-            t.visit(&mut out_nested);
-        }
-        fn visit_value(&mut self, _: u8) {
-            assert_eq!(self.0[self.1], 0);
-            self.0[self.1] = u32::max_value();
+/// Byte pattern for a shape of subtree, see CompressedOutput::write_byte_pattern_use.
+///
+/// Only used when encoding.
+#[derive(Clone)]
+struct ShapeState {
+    /// The first subtree seen with this shape, None for shapes seeded from a schema.
+    first: Option<NodeId>,
+    /// Set once a second subtree with this shape is seen, and generalized whenever a subtree does not match it.
+    pattern: Option<Rc<BytePatternTemplate>>,
+}
+
+/// Writes PrefixSizedEncoding directly from a View.
+struct SizedOutput<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl<'a> SizedOutput<'a> {
+    fn write_node<T: View<Value = Vec<u8>>>(&mut self, t: &T) {
+        let counter = t.apply(Counter {
+            count: 0,
+            value: None,
+        });
+        if let Some(value) = counter.value {
+            write_value(self.out, &value).unwrap();
+            return;
         }
+        write_list_marker(self.out, counter.count).unwrap();
+        let size_position = self.out.len();
+        self.out.extend_from_slice(&[0; 8]);
+        t.visit(self);
+        let size = (self.out.len() - size_position - 8) as u64;
+        LittleEndian::write_u64(&mut self.out[size_position..size_position + 8], size);
     }
+}
 
-    let mut counts = vec![0];
-    let mut out = Out(&mut counts, 0);
-    view.visit(&mut out);
+impl<'a> Visitor for SizedOutput<'a> {
+    type Value = Vec<u8>;
+    fn visit_list<T: View<Value = Self::Value>>(&mut self, t: &T) {
+        self.write_node(t);
+    }
+    fn visit_value(&mut self, _t: Self::Value) {
+        // Values are written by write_node, when counting
+    }
+}
 
-    Shape { counts }
+/// Lazy view of a node encoded with PrefixSizedEncoding.
+///
+/// Nothing is decoded until accessed, and accessing a child only reads the list markers of its preceding siblings,
+/// so reading a few fields of a large document only touches a small part of it.
+/// For the same reason, only the nodes accessed are checked: accessing a malformed node panics.
+#[derive(Clone, Copy)]
+pub struct SizedNode<'a> {
+    /// Exactly the encoding of this node.
+    data: &'a [u8],
 }
 
-struct ShapeState {
-    id: u32,
-    // Pushed in post order traversal order
-    // TODO: store refs in here? Remove this?
-    templates: Vec<Concrete<u8>>,
-    // TODO: store refs in here?
-    template_map: HashMap<Concrete<u8>, u32>,
+impl<'a> SizedNode<'a> {
+    /// View of an encoded document's root.
+    ///
+    /// Fails with io::ErrorKind::InvalidData if data does not hold exactly one node (the root's children are not checked).
+    pub fn new(data: &'a [u8]) -> io::Result<SizedNode<'a>> {
+        let (node, rest) = split_sized_node(data)?;
+        if !rest.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected data after root",
+            ));
+        }
+        Ok(node)
+    }
 
-    // shape + Vec<u8> = Concrete<u8>
-    // when encoding check these, see if there is an existing on to reference that matches exactly (use map above)
-    // can also check to find the most similar one, and make partial replace template if profitable (or maybe only do that with schema hints)
-    trees: Vec<Vec<u8>>,
-}
+    /// The value, if this is a value node.
+    pub fn value(&self) -> Option<&'a [u8]> {
+        let marker = self.data[0];
+        if marker == VALUE_MARKER || (INLINE_BYTES_MIN..INLINE_LIST_MIN).contains(&marker) {
+            Some(&self.data[1..])
+        } else if marker == BYTES_MARKER {
+            let mut rdr = Cursor::new(&self.data[1..]);
+            read_varint(&mut rdr).unwrap();
+            Some(&self.data[1 + rdr.position() as usize..])
+        } else {
+            None
+        }
+    }
 
-struct State {
-    // Pushed in post order traversal order
-    templates: Vec<Concrete<u8>>,
-    template_map: HashMap<Concrete<u8>, u32>,
-    //all: HashMap<Shape, ShapeState>,
+    /// The children, in order. Value nodes have none.
+    pub fn children(&self) -> SizedChildren<'a> {
+        match self.parse() {
+            (Marker::List(count), content) => SizedChildren {
+                content,
+                remaining: count,
+            },
+            _ => SizedChildren {
+                content: &[],
+                remaining: 0,
+            },
+        }
+    }
+
+    pub fn child(&self, index: usize) -> Option<SizedNode<'a>> {
+        self.children().nth(index)
+    }
+
+    /// Returns the marker and the encoded children.
+    fn parse(&self) -> (Marker, &'a [u8]) {
+        let mut rdr = Cursor::new(self.data);
+        let marker = read_marker(&mut rdr);
+        if let Marker::List(_) = marker {
+            // Skip the size, which split_sized_node already checked.
+            let start = (rdr.position() as usize)
+                .checked_add(8)
+                .and_then(|start| self.data.get(start..))
+                .expect("List size exceeds the data");
+            (marker, start)
+        } else {
+            (marker, &[])
+        }
+    }
 }
 
-impl State {
-    fn new() -> State {
-        State {
-            templates: vec![],
-            template_map: HashMap::new(),
+/// Splits the node at the start of data from what follows it.
+fn split_sized_node(data: &[u8]) -> io::Result<(SizedNode<'_>, &[u8])> {
+    let mut rdr = Cursor::new(data);
+    let length = match try_read_marker(&mut rdr)? {
+        Marker::List(_) => {
+            let size = rdr.read_u64::<LittleEndian>()?;
+            usize::try_from(size)
+                .ok()
+                .and_then(|size| (rdr.position() as usize).checked_add(size))
         }
+        Marker::Value(value) => Some(value_marker_size(value.len()) + value.len()),
+        Marker::Other(marker) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid marker {}", marker),
+            ))
+        }
+    };
+    match length {
+        Some(length) if length <= data.len() => {
+            let (node, rest) = data.split_at(length);
+            Ok((SizedNode { data: node }, rest))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Node size exceeds the data",
+        )),
     }
-    fn record(&mut self, c: &Concrete<u8>) {
-        let mut inserted = false;
-        let len = self.templates.len() as u32;
-        self.template_map.entry(c.clone()).or_insert_with(|| {
-            inserted = true;
-            len
-        });
-        if inserted {
-            self.templates.push(c.clone());
+}
+
+pub struct SizedChildren<'a> {
+    content: &'a [u8],
+    remaining: usize,
+}
+
+impl<'a> Iterator for SizedChildren<'a> {
+    type Item = SizedNode<'a>;
+    fn next(&mut self) -> Option<SizedNode<'a>> {
+        if self.remaining == 0 {
+            assert!(self.content.is_empty(), "List size exceeds its children");
+            return None;
         }
+        self.remaining -= 1;
+        let (node, rest) = split_sized_node(self.content).unwrap();
+        self.content = rest;
+        Some(node)
     }
-    fn lookup(&mut self, c: &Concrete<u8>) -> Option<&u32> {
-        self.template_map.get(c)
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
     }
 }
 
-fn prefix_encode_compressed(state: &mut State, c: &Concrete<u8>, out: &mut Vec<u8>) {
-    match c {
-        Concrete::List(list) => {
-            let id = state.lookup(c);
-            match id {
-                Some(index) => {
-                    out.push(TEMPLATE_USE_MARKER);
-                    out.write_u32::<LittleEndian>(*index).unwrap();
-                }
-                None => {
-                    write_list_marker(out, list.len());
-                    for child in list {
-                        prefix_encode_compressed(state, child, out);
-                    }
+impl<'a> ExactSizeIterator for SizedChildren<'a> {}
+
+impl<'a> View for SizedNode<'a> {
+    type Value = Vec<u8>;
+    fn visit<V: Visitor<Value = Vec<u8>>>(&self, v: &mut V) {
+        match self.value() {
+            Some(value) => v.visit_value(value.to_vec()),
+            None => {
+                for child in self.children() {
+                    v.visit_list(&child);
                 }
             }
-            state.record(c);
-        }
-        Concrete::Value(v) => {
-            out.push(VALUE_MARKER);
-            out.push(*v);
         }
     }
 }
 
-fn prefix_decode_compressed<T: ReadBytesExt>(state: &mut State, input: &mut T) -> Concrete<u8> {
-    let marker = read_marker(input);
-    match marker {
-        Marker::List(count) => {
+/// View of the tree a use of a TreeTemplate generates from a tree stream.
+/// The tree is expanded as it is visited: holes are only decoded when visited.
+///
+/// The tree stream holds, in pre-order, each hole as a node in PrefixEncoding,
+/// and the data stream of each BytePatternTemplateUse.
+/// This is the format PrefixCompressedEncoding documents use for tree template uses, and SchemaEncoding writes,
+/// except that holes written by PrefixCompressedEncoding can also use the document's templates, so can only be read with it.
+pub struct TreeTemplateView<'a> {
+    template: &'a TreeTemplate,
+    /// The stream starting at this node's first hole.
+    stream: &'a [u8],
+}
+
+impl<'a> TreeTemplateView<'a> {
+    /// Fails with io::ErrorKind::InvalidData (or io::ErrorKind::UnexpectedEof if it is truncated)
+    /// if stream is not a tree stream for template.
+    pub fn new(template: &'a TreeTemplate, stream: &'a [u8]) -> io::Result<TreeTemplateView<'a>> {
+        if !skip_tree_stream(template, stream)?.is_empty() {
+            return Err(invalid_data(
+                "Unexpected data after tree stream".to_string(),
+            ));
+        }
+        Ok(TreeTemplateView { template, stream })
+    }
+}
+
+/// The rest of stream after the holes of template.
+fn skip_tree_stream<'a>(template: &TreeTemplate, stream: &'a [u8]) -> io::Result<&'a [u8]> {
+    match template {
+        TreeTemplate::List(children) => children
+            .iter()
+            .try_fold(stream, |stream, child| skip_tree_stream(child, stream)),
+        TreeTemplate::ConstantValue(_) => Ok(stream),
+        TreeTemplate::ValueFromStream => {
+            if let Marker::List(_) = try_read_marker(&mut Cursor::new(stream))? {
+                return Err(invalid_data("Tree template expected a value".to_string()));
+            }
+            Ok(split_prefix_node(stream)?.1)
+        }
+        TreeTemplate::TreeFromStream => Ok(split_prefix_node(stream)?.1),
+        TreeTemplate::TreeTemplateUse(template) => skip_tree_stream(template, stream),
+        TreeTemplate::BytePatternTemplateUse(pattern) => stream
+            .get(pattern.size as usize..)
+            .ok_or_else(|| invalid_data("Data stream exceeds the tree stream".to_string())),
+    }
+}
+
+/// Splits the PrefixEncoding node at the start of data from what follows it.
+fn split_prefix_node(data: &[u8]) -> io::Result<(&[u8], &[u8])> {
+    let mut rdr = Cursor::new(data);
+    // Nodes started but not yet read, so deep trees do not need deep recursion.
+    let mut remaining: usize = 1;
+    while remaining > 0 {
+        remaining -= 1;
+        match try_read_marker(&mut rdr)? {
+            Marker::List(count) => {
+                remaining = remaining
+                    .checked_add(count)
+                    .ok_or_else(|| invalid_data("List count overflows".to_string()))?;
+            }
+            Marker::Value(_) => {}
+            Marker::Other(marker) => {
+                return Err(invalid_data(format!("Invalid marker {}", marker)));
+            }
+        }
+    }
+    Ok(data.split_at(rdr.position() as usize))
+}
+
+impl<'a> View for TreeTemplateView<'a> {
+    type Value = Vec<u8>;
+    fn visit<V: Visitor<Value = Vec<u8>>>(&self, v: &mut V) {
+        // new checked the whole stream, so reading it can not fail.
+        match self.template {
+            TreeTemplate::List(children) => {
+                let mut stream = self.stream;
+                for child in children {
+                    v.visit_list(&TreeTemplateView {
+                        template: child,
+                        stream,
+                    });
+                    // Skipped separately, since v might not visit the child.
+                    stream = skip_tree_stream(child, stream).unwrap();
+                }
+            }
+            TreeTemplate::ConstantValue(value) => v.visit_value(value.clone()),
+            TreeTemplate::ValueFromStream | TreeTemplate::TreeFromStream => {
+                let node = split_prefix_node(self.stream).unwrap().0;
+                PrefixEncoding.visit_root(node, v)
+            }
+            TreeTemplate::TreeTemplateUse(template) => TreeTemplateView {
+                template,
+                stream: self.stream,
+            }
+            .visit(v),
+            TreeTemplate::BytePatternTemplateUse(pattern) => {
+                BytePatternView::new(pattern, &self.stream[..pattern.size as usize]).visit(v)
+            }
+        }
+    }
+}
+
+/// Index of a distinct subtree in a State.
+type NodeId = u32;
+
+/// A subtree, with its children interned.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+enum Node {
+    List(Vec<NodeId>),
+    Value(Vec<u8>),
+}
+
+/// Facts about a node, computed from those of its children when it is interned.
+#[derive(Clone)]
+struct NodeInfo {
+    /// Size in PrefixEncoding, an estimate of its size without templates.
+    plain_size: usize,
+    /// Number of values, to find value positions for TEMPLATE_USE_PATCHED_MARKER.
+    value_count: usize,
+    /// Total length of the values.
+    value_size: usize,
+    shape: ShapeId,
+    structure: ShapeId,
+}
+
+/// Hash of a node, which only depends on the ids of its children (not their content), since equal subtrees have the same id.
+fn node_hash(node: &Node) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    node.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A template which TEMPLATE_USE_MARKER can reference.
+#[derive(Clone)]
+enum Template {
+    /// A previous subtree, reproduced exactly.
+    Subtree(NodeId),
+    /// Uses are followed by the template's data stream.
+    BytePattern(Rc<BytePatternTemplate>, Rc<Expansion>),
+    /// Uses are followed by the trees filling its holes.
+    Tree(Rc<TreeTemplate>, Rc<Expansion>),
+}
+
+/// A template prepared for decoding: its constant subtrees are interned when it is added,
+/// so each use only interns the nodes holding data from the stream, instead of copying the whole template.
+enum Expansion {
+    /// A subtree with no data from the stream.
+    Constant(NodeId),
+    List(Vec<Expansion>),
+    /// A value from a byte pattern's data stream.
+    DataValue {
+        offset: u32,
+        length: u32,
+    },
+    /// A byte pattern used within a tree template, whose data stream (of size bytes) is read from the tree stream.
+    BytePattern {
+        size: u32,
+        content: Rc<Expansion>,
+    },
+    /// A value from the tree stream.
+    StreamValue,
+    /// A tree from the tree stream.
+    StreamTree,
+}
+
+impl Expansion {
+    /// A list, which is a constant if all its children are.
+    fn list(state: &mut State, children: Vec<Expansion>) -> Expansion {
+        let constants: Option<Vec<NodeId>> = children
+            .iter()
+            .map(|child| match child {
+                Expansion::Constant(id) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        match constants {
+            Some(constants) => Expansion::Constant(state.intern(Node::List(constants))),
+            None => Expansion::List(children),
+        }
+    }
+}
+
+/// Tree template for lists with the same TreeHead, see CompressedOutput::write_tree_template_use.
+#[derive(Clone)]
+struct TreeShapeUse {
+    /// The first list seen with this head, None for heads seeded from a schema.
+    first: Option<NodeId>,
+    /// Set once a second list with this head is seen, and generalized whenever a list does not match it.
+    template: Option<Rc<TreeTemplate>>,
+}
+
+/// The children of a list, with list children reduced to their length.
+/// Lists with the same head are likely to be instances of the same type (for example structs with the same type name and field count).
+#[derive(PartialEq, Eq, Hash, Clone)]
+enum TreeHead {
+    Value(Vec<u8>),
+    List(usize),
+}
+
+struct State {
+    /// The state this one continues from, which is shared rather than copied (see TemplateDictionary).
+    /// Its nodes, shapes and templates come first, so keep their ids and indexes, and only what is added after it is stored here.
+    /// Lookups fall through to it, and encoder entries changed after it are copied here.
+    base: Option<Rc<State>>,
+    /// Id of nodes[0] (the number of nodes in base).
+    first_node: NodeId,
+    /// Id of the first shape in shape_ids (the number of shapes in base).
+    first_shape: ShapeId,
+    /// Every distinct subtree seen so far, so equal subtrees share a NodeId.
+    /// Children are referenced by id, so each subtree is stored once, no matter how many ancestors it has.
+    /// Nodes are stored once: node_ids holds the ids of the nodes with each hash (see node_hash).
+    nodes: Vec<Node>,
+    node_ids: HashMap<u64, Vec<NodeId>>,
+    info: Vec<NodeInfo>,
+    shape_ids: HashMap<ShapeNode, ShapeId>,
+    // Pushed in the order they are encoded (post order traversal order for subtrees)
+    templates: VecDeque<Template>,
+    /// Number of templates evicted from the front of templates (or in base), which is the index of its first template.
+    evicted: u32,
+    /// If set, only this many of the latest templates are kept (see PrefixWindowedEncoding).
+    max_templates: Option<usize>,
+    template_map: HashMap<NodeId, u32>,
+    byte_pattern_map: HashMap<Rc<BytePatternTemplate>, u32>,
+    tree_template_map: HashMap<Rc<TreeTemplate>, u32>,
+    /// Only used when encoding.
+    shapes: HashMap<ShapeId, ShapeState>,
+    /// Only used when encoding.
+    tree_shapes: HashMap<Vec<TreeHead>, TreeShapeUse>,
+    /// The latest list written with each structure, which later ones can be patched from.
+    /// Only used when encoding.
+    similar: HashMap<ShapeId, NodeId>,
+    /// How many more nodes templates may expand to, so a small document can not make the decoder allocate without limit
+    /// (see decode_compressed_document). Only used when decoding.
+    budget: usize,
+}
+
+impl State {
+    fn new() -> State {
+        State {
+            base: None,
+            first_node: 0,
+            first_shape: 0,
+            nodes: vec![],
+            node_ids: HashMap::new(),
+            info: vec![],
+            shape_ids: HashMap::new(),
+            templates: VecDeque::new(),
+            evicted: 0,
+            max_templates: None,
+            template_map: HashMap::new(),
+            byte_pattern_map: HashMap::new(),
+            tree_template_map: HashMap::new(),
+            shapes: HashMap::new(),
+            tree_shapes: HashMap::new(),
+            similar: HashMap::new(),
+            budget: usize::MAX,
+        }
+    }
+    /// A state continuing from base, which is never modified (base's templates are never evicted).
+    fn with_base(base: Rc<State>) -> State {
+        State {
+            first_node: base.next_node(),
+            first_shape: base.next_shape(),
+            evicted: base.next_index(),
+            base: Some(base),
+            ..State::new()
+        }
+    }
+    fn next_node(&self) -> NodeId {
+        self.first_node + self.nodes.len() as NodeId
+    }
+    fn next_shape(&self) -> ShapeId {
+        self.first_shape + self.shape_ids.len() as ShapeId
+    }
+    fn node(&self, id: NodeId) -> &Node {
+        match id.checked_sub(self.first_node) {
+            Some(offset) => &self.nodes[offset as usize],
+            None => self.base.as_ref().unwrap().node(id),
+        }
+    }
+    fn info(&self, id: NodeId) -> &NodeInfo {
+        match id.checked_sub(self.first_node) {
+            Some(offset) => &self.info[offset as usize],
+            None => self.base.as_ref().unwrap().info(id),
+        }
+    }
+    /// The id of node, if it has been interned. hash is its node_hash.
+    fn find(&self, node: &Node, hash: u64) -> Option<NodeId> {
+        if let Some(id) = self.base.as_ref().and_then(|base| base.find(node, hash)) {
+            return Some(id);
+        }
+        let ids = self.node_ids.get(&hash)?;
+        ids.iter().find(|id| self.node(**id) == node).cloned()
+    }
+    fn intern(&mut self, node: Node) -> NodeId {
+        let hash = node_hash(&node);
+        if let Some(id) = self.find(&node, hash) {
+            return id;
+        }
+
+        let info = match &node {
+            Node::List(children) => {
+                let children: Vec<&NodeInfo> =
+                    children.iter().map(|child| self.info(*child)).collect();
+                let shape = ShapeNode::List(children.iter().map(|child| child.shape).collect());
+                let structure =
+                    ShapeNode::List(children.iter().map(|child| child.structure).collect());
+                NodeInfo {
+                    plain_size: list_marker_size(children.len())
+                        + children.iter().map(|child| child.plain_size).sum::<usize>(),
+                    value_count: children.iter().map(|child| child.value_count).sum(),
+                    value_size: children.iter().map(|child| child.value_size).sum(),
+                    shape: self.intern_shape(shape),
+                    structure: self.intern_shape(structure),
+                }
+            }
+            Node::Value(value) => NodeInfo {
+                plain_size: value_marker_size(value.len()) + value.len(),
+                value_count: 1,
+                value_size: value.len(),
+                shape: self.intern_shape(ShapeNode::Value(Some(value.len()))),
+                structure: self.intern_shape(ShapeNode::Value(None)),
+            },
+        };
+        let id = self.next_node();
+        self.nodes.push(node);
+        self.info.push(info);
+        self.node_ids.entry(hash).or_default().push(id);
+        id
+    }
+    fn shape_id(&self, shape: &ShapeNode) -> Option<ShapeId> {
+        self.base
+            .as_ref()
+            .and_then(|base| base.shape_id(shape))
+            .or_else(|| self.shape_ids.get(shape).cloned())
+    }
+    fn intern_shape(&mut self, shape: ShapeNode) -> ShapeId {
+        if let Some(id) = self.shape_id(&shape) {
+            return id;
+        }
+        let id = self.next_shape();
+        self.shape_ids.insert(shape, id);
+        id
+    }
+    fn intern_view<T: View<Value = Vec<u8>>>(&mut self, t: &T) -> NodeId {
+        let mut interner = Interner {
+            state: self,
+            children: vec![],
+            value: None,
+        };
+        t.visit(&mut interner);
+        let Interner {
+            children, value, ..
+        } = interner;
+        match value {
+            Some(value) => {
+                assert!(children.is_empty(), "Value nodes can not have children");
+                self.intern(Node::Value(value))
+            }
+            None => self.intern(Node::List(children)),
+        }
+    }
+    fn with_max_templates(max_templates: NonZeroU32) -> State {
+        State {
+            max_templates: Some(max_templates.get() as usize),
+            ..State::new()
+        }
+    }
+    fn record(&mut self, id: NodeId) {
+        if self.lookup(id).is_none() {
+            let index = self.push_template(Template::Subtree(id));
+            self.template_map.insert(id, index);
+        }
+    }
+    fn lookup(&self, id: NodeId) -> Option<u32> {
+        self.template_map
+            .get(&id)
+            .cloned()
+            .or_else(|| self.base.as_ref()?.lookup(id))
+    }
+    fn byte_pattern_index(&self, pattern: &BytePatternTemplate) -> Option<u32> {
+        self.byte_pattern_map
+            .get(pattern)
+            .cloned()
+            .or_else(|| self.base.as_ref()?.byte_pattern_index(pattern))
+    }
+    fn tree_template_index(&self, template: &TreeTemplate) -> Option<u32> {
+        self.tree_template_map
+            .get(template)
+            .cloned()
+            .or_else(|| self.base.as_ref()?.tree_template_index(template))
+    }
+    fn shape_state(&self, shape: ShapeId) -> Option<&ShapeState> {
+        self.shapes
+            .get(&shape)
+            .or_else(|| self.base.as_ref()?.shape_state(shape))
+    }
+    fn tree_shape(&self, head: &[TreeHead]) -> Option<&TreeShapeUse> {
+        self.tree_shapes
+            .get(head)
+            .or_else(|| self.base.as_ref()?.tree_shape(head))
+    }
+    fn similar(&self, structure: ShapeId) -> Option<NodeId> {
+        self.similar
+            .get(&structure)
+            .cloned()
+            .or_else(|| self.base.as_ref()?.similar(structure))
+    }
+    /// Takes amount nodes from budget, failing if the document expands to more than it allows.
+    fn charge(&mut self, amount: usize) -> io::Result<()> {
+        self.budget = self.budget.checked_sub(amount).ok_or_else(|| {
+            invalid_data("Document expands to more nodes than the decoder allows".to_string())
+        })?;
+        Ok(())
+    }
+    fn add_byte_pattern(&mut self, pattern: Rc<BytePatternTemplate>) -> u32 {
+        let expansion = Rc::new(byte_pattern_expansion(self, &pattern.content, 0));
+        let index = self.push_template(Template::BytePattern(pattern.clone(), expansion));
+        self.byte_pattern_map.insert(pattern, index);
+        index
+    }
+    fn add_tree_template(&mut self, template: Rc<TreeTemplate>) -> u32 {
+        let expansion = Rc::new(tree_template_expansion(self, &template));
+        let index = self.push_template(Template::Tree(template.clone(), expansion));
+        self.tree_template_map.insert(template, index);
+        index
+    }
+    /// The index the next template will have.
+    fn next_index(&self) -> u32 {
+        self.evicted + self.templates.len() as u32
+    }
+    /// The template at index, if it has been added and not evicted.
+    fn template(&self, index: u32) -> Option<&Template> {
+        match index.checked_sub(self.evicted) {
+            Some(offset) => self.templates.get(offset as usize),
+            None => self.base.as_ref()?.template(index),
+        }
+    }
+    /// Appends a template, evicting the oldest ones outside the window.
+    /// The encoder and decoder add the same templates in the same order, so they evict the same ones.
+    fn push_template(&mut self, template: Template) -> u32 {
+        let index = self.next_index();
+        self.templates.push_back(template);
+        while self.templates.len() > self.max_templates.unwrap_or(usize::MAX) {
+            let evicted = self.evicted;
+            // Maps are only updated if they still refer to this index, as the same template may have been added again since.
+            match self.templates.pop_front().unwrap() {
+                Template::Subtree(id) => {
+                    self.template_map.remove(&id);
+                }
+                Template::BytePattern(pattern, _) => {
+                    if self.byte_pattern_map.get(&pattern) == Some(&evicted) {
+                        self.byte_pattern_map.remove(&pattern);
+                    }
+                }
+                Template::Tree(template, _) => {
+                    if self.tree_template_map.get(&template) == Some(&evicted) {
+                        self.tree_template_map.remove(&template);
+                    }
+                }
+            }
+            self.evicted += 1;
+        }
+        index
+    }
+}
+
+/// Interns the children of a node, or finds its value.
+struct Interner<'a> {
+    state: &'a mut State,
+    children: Vec<NodeId>,
+    value: Option<Vec<u8>>,
+}
+
+impl<'a> Visitor for Interner<'a> {
+    type Value = Vec<u8>;
+    fn visit_list<T: View<Value = Self::Value>>(&mut self, t: &T) {
+        let id = self.state.intern_view(t);
+        self.children.push(id);
+    }
+    fn visit_value(&mut self, t: Self::Value) {
+        self.value = Some(t);
+    }
+}
+
+/// Values are only recorded as templates when they are long enough that referencing them
+/// (a TEMPLATE_USE_MARKER and a varint index, usually 2 or 3 bytes) is likely smaller than writing them (a marker and the value),
+/// so short values do not use up template indexes.
+/// The decoder records the same values, so this can only depend on the value itself.
+fn is_recorded_value(value: &[u8]) -> bool {
+    value.len() > 4
+}
+
+fn write_template_use<W: Write>(out: &mut W, index: u32) -> io::Result<()> {
+    out.write_u8(TEMPLATE_USE_MARKER)?;
+    write_varint(out, index as u64)
+}
+
+/// Size of a TEMPLATE_USE_MARKER and its index.
+fn template_use_size(index: u32) -> usize {
+    1 + varint_size(index as u64)
+}
+
+/// Writes PrefixCompressedEncoding from interned nodes, streaming it to out.
+///
+/// Each node is written as the first of these which applies:
+/// a TEMPLATE_USE of an identical previous subtree, a byte pattern template,
+/// a patched use of a similar previous subtree, a tree template, or plainly.
+struct CompressedOutput<'a, W> {
+    state: &'a mut State,
+    out: &'a mut W,
+    options: &'a CompressionOptions,
+}
+
+/// Siblings using the same template, which are written as one TEMPLATE_USE_SEQUENCE (or TEMPLATE_USE_COLUMNS) once the run ends.
+///
+/// This is the only output held back: the run's length is written before the uses' data streams, so they are kept until then.
+/// Tree template uses are never added to runs, as they are followed by subtrees which would have to be held too.
+struct Run {
+    index: u32,
+    count: usize,
+    /// The data streams of the uses, one after another (empty for subtrees).
+    data: Vec<u8>,
+}
+
+impl<'a, W: Write> CompressedOutput<'a, W> {
+    fn write_node(&mut self, id: NodeId) -> io::Result<()> {
+        let mut run = None;
+        self.write_sibling(id, &mut run)?;
+        self.end_run(&mut run)
+    }
+
+    /// Writes the node id after the siblings in run. Uses of templates are added to run, anything else ends it first.
+    fn write_sibling(&mut self, id: NodeId, run: &mut Option<Run>) -> io::Result<()> {
+        if let Some(index) = self.state.lookup(id) {
+            // Small subtrees (like empty lists) are smaller written again than referenced.
+            if self.options.subtree_templates && self.saves(template_use_size(index), id) {
+                return self.add_use(run, index, &[]);
+            }
+        }
+        let children = match self.state.node(id) {
+            Node::Value(_) => return self.write_value_node(id, run),
+            Node::List(children) => children.clone(),
+        };
+
+        if !self.write_byte_pattern_use(id, run)?
+            && !self.write_patched_use(id, run)?
+            && !self.write_tree_template_use(id, run)?
+        {
+            self.end_run(run)?;
+            write_list_marker(self.out, children.len())?;
+            let mut children_run = None;
+            for child in children {
+                self.write_sibling(child, &mut children_run)?;
+            }
+            self.end_run(&mut children_run)?;
+        }
+        self.state.record(id);
+        let structure = self.state.info(id).structure;
+        self.state.similar.insert(structure, id);
+        Ok(())
+    }
+
+    fn write_value_node(&mut self, id: NodeId, run: &mut Option<Run>) -> io::Result<()> {
+        self.end_run(run)?;
+        let recorded = match self.state.node(id) {
+            Node::Value(value) => {
+                write_value(self.out, value)?;
+                is_recorded_value(value)
+            }
+            Node::List(_) => unreachable!("Not a value"),
+        };
+        if recorded {
+            self.state.record(id);
+        }
+        Ok(())
+    }
+
+    /// Adds a use of the template at index, followed by data, to run (ending it first if it uses another template).
+    fn add_use(&mut self, run: &mut Option<Run>, index: u32, data: &[u8]) -> io::Result<()> {
+        match run {
+            Some(run) if run.index == index && self.options.sequences => {}
+            _ => {
+                self.end_run(run)?;
+                *run = Some(Run {
+                    index,
+                    count: 0,
+                    data: vec![],
+                });
+            }
+        }
+        let run = run.as_mut().unwrap();
+        run.count += 1;
+        run.data.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Writes the uses in run: a single use as a TEMPLATE_USE, otherwise a TEMPLATE_USE_SEQUENCE,
+    /// or a TEMPLATE_USE_COLUMNS for byte patterns with more than one value from the stream.
+    fn end_run(&mut self, run: &mut Option<Run>) -> io::Result<()> {
+        let run = match run.take() {
+            Some(run) => run,
+            None => return Ok(()),
+        };
+        if run.count == 1 {
+            write_template_use(self.out, run.index)?;
+            return self.out.write_all(&run.data);
+        }
+        let mut slots = vec![];
+        if let Some(Template::BytePattern(pattern, _)) = self.state.template(run.index) {
+            stream_slots(&pattern.content, 0, &mut slots);
+        }
+        if self.options.columns && slots.len() > 1 {
+            self.out.write_u8(TEMPLATE_USE_COLUMNS_MARKER)?;
+            write_varint(self.out, run.index as u64)?;
+            write_varint(self.out, run.count as u64)?;
+            let size = run.data.len() / run.count;
+            for (offset, length) in slots {
+                let range = offset as usize..(offset + length) as usize;
+                for data in run.data.chunks(size) {
+                    self.out.write_all(&data[range.clone()])?;
+                }
+            }
+            Ok(())
+        } else {
+            self.out.write_u8(TEMPLATE_USE_SEQUENCE_MARKER)?;
+            write_varint(self.out, run.index as u64)?;
+            write_varint(self.out, run.count as u64)?;
+            self.out.write_all(&run.data)
+        }
+    }
+
+    /// If writing size bytes for the node id saves at least options.min_savings bytes, compared to writing it plainly.
+    fn saves(&self, size: usize, id: NodeId) -> bool {
+        size + self.options.min_savings <= self.state.info(id).plain_size
+    }
+
+    /// Writes the list id as the latest list with the same structure with its differing values replaced,
+    /// if that is smaller than writing it plainly.
+    ///
+    /// This covers subtrees which differ from a previous one in a few values, when a byte pattern does not apply
+    /// (because it is the first subtree with its value lengths).
+    fn write_patched_use(&mut self, id: NodeId, run: &mut Option<Run>) -> io::Result<bool> {
+        if !self.options.patches {
+            return Ok(false);
+        }
+        let state = &*self.state;
+        let base = match state.similar(state.info(id).structure) {
+            Some(base) => base,
+            None => return Ok(false),
+        };
+        let mut replacements = vec![];
+        if !diff_values(state, base, id, &mut 0, &mut replacements) {
+            return Ok(false);
+        }
+
+        // The base may have been evicted from the template window
+        let index = match state.lookup(base) {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+        let mut size = 1 + varint_size(index as u64) + varint_size(replacements.len() as u64);
+        let mut next = 0;
+        for (position, value) in &replacements {
+            size += varint_size((position - next) as u64);
+            size += match state.lookup(*value) {
+                Some(index) => template_use_size(index),
+                None => state.info(*value).plain_size,
+            };
+            next = position + 1;
+        }
+        if !self.saves(size, id) {
+            return Ok(false);
+        }
+
+        self.end_run(run)?;
+        self.out.write_u8(TEMPLATE_USE_PATCHED_MARKER)?;
+        write_varint(self.out, index as u64)?;
+        write_varint(self.out, replacements.len() as u64)?;
+        let mut next = 0;
+        for (position, value) in replacements {
+            write_varint(self.out, (position - next) as u64)?;
+            self.write_node(value)?;
+            next = position + 1;
+        }
+        Ok(true)
+    }
+
+    /// Writes the list id as a use of a byte pattern template, if that is smaller than writing it plainly.
+    ///
+    /// The first subtree of each shape (structure and value lengths) is written normally.
+    /// The second defines a byte pattern template, where values that were the same in both are constants.
+    /// Later ones use it, so only their differing values are written.
+    /// A subtree which does not match the pattern generalizes it (its mismatched constants are taken from the stream instead),
+    /// so every subtree of a shape after the first is written as a use of the shape's latest pattern.
+    fn write_byte_pattern_use(&mut self, id: NodeId, run: &mut Option<Run>) -> io::Result<bool> {
+        if !self.options.byte_patterns {
+            return Ok(false);
+        }
+        let state = &mut *self.state;
+        let info = state.info(id);
+        if info.value_size == 0 {
+            return Ok(false);
+        }
+        let shape = info.shape;
+        let mut shape_state = match state.shape_state(shape) {
+            Some(shape_state) if shape_state.first == Some(id) => return Ok(false),
+            Some(shape_state) => shape_state.clone(),
+            None => {
+                let first = ShapeState {
+                    first: Some(id),
+                    pattern: None,
+                };
+                state.shapes.insert(shape, first);
+                return Ok(false);
+            }
+        };
+        let matched = shape_state.pattern.as_ref().and_then(|pattern| {
+            node_byte_pattern_data(state, id, pattern).map(|data| (pattern.clone(), data))
+        });
+        let (pattern, data) = match matched {
+            Some(matched) => matched,
+            None => {
+                let mut size = 0;
+                let content = match &shape_state.pattern {
+                    Some(pattern) => generalize_pattern(state, &pattern.content, id, &mut size),
+                    None => merge_pattern(state, shape_state.first.unwrap(), id, &mut size),
+                };
+                let pattern = Rc::new(BytePatternTemplate { size, content });
+                let data = node_byte_pattern_data(state, id, &pattern)
+                    .expect("Subtree must match its generalized pattern");
+                shape_state.pattern = Some(pattern.clone());
+                state.shapes.insert(shape, shape_state);
+                (pattern, data)
+            }
+        };
+
+        let index = state.byte_pattern_index(&pattern);
+        let use_size = template_use_size(index.unwrap_or(state.next_index()));
+        let plain_size = state.info(id).plain_size;
+        if pattern.size == 0 || use_size + data.len() + self.options.min_savings > plain_size {
+            return Ok(false);
+        }
+
+        match index {
+            Some(index) => self.add_use(run, index, &data)?,
+            None => {
+                self.end_run(run)?;
+                self.out.write_u8(BYTE_PATTERN_TEMPLATE_MARKER)?;
+                write_varint(self.out, pattern.size as u64)?;
+                write_byte_pattern(self.state, self.out, &pattern.content)?;
+                self.state.add_byte_pattern(pattern);
+                self.out.write_all(&data)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Writes the list id as a use of a tree template, if the template's constant parts are larger than a TEMPLATE_USE.
+    ///
+    /// Lists with the same TreeHead share a template, which starts as the parts common to the first two lists,
+    /// and is generalized (constants and mismatched subtrees replaced with holes) when a list does not match it.
+    fn write_tree_template_use(&mut self, id: NodeId, run: &mut Option<Run>) -> io::Result<bool> {
+        if !self.options.tree_templates {
+            return Ok(false);
+        }
+        let state = &mut *self.state;
+        let head: Vec<TreeHead> = match state.node(id) {
+            Node::List(children) => children
+                .iter()
+                .map(|child| match state.node(*child) {
+                    Node::List(grandchildren) => TreeHead::List(grandchildren.len()),
+                    Node::Value(value) => TreeHead::Value(value.clone()),
+                })
+                .collect(),
+            Node::Value(_) => return Ok(false),
+        };
+        let mut shape_use = match state.tree_shape(&head) {
+            Some(shape_use) => shape_use.clone(),
+            None => {
+                let first = TreeShapeUse {
+                    first: Some(id),
+                    template: None,
+                };
+                state.tree_shapes.insert(head.clone(), first.clone());
+                first
+            }
+        };
+        let mut holes = vec![];
+        let template = match &shape_use.template {
+            Some(template) if match_tree_template(state, id, template, &mut holes) => {
+                template.clone()
+            }
+            _ => {
+                if shape_use.first == Some(id) {
+                    return Ok(false);
+                }
+                let base = match &shape_use.template {
+                    Some(template) => (**template).clone(),
+                    None => constant_tree_template(state, shape_use.first.unwrap()),
+                };
+                let template = Rc::new(generalize_tree_template(&base, state, id));
+                holes.clear();
+                assert!(match_tree_template(state, id, &template, &mut holes));
+                shape_use.template = Some(template.clone());
+                state.tree_shapes.insert(head, shape_use);
+                template
+            }
+        };
+        let use_size = template_use_size(state.next_index());
+        if constant_size(&template, use_size) < use_size + self.options.min_savings {
+            return Ok(false);
+        }
+
+        self.end_run(run)?;
+        match self.state.tree_template_index(&template) {
+            Some(index) => write_template_use(self.out, index)?,
+            None => {
+                self.out.write_u8(TREE_TEMPLATE_MARKER)?;
+                write_tree_template(self.state, self.out, &template, true)?;
+                self.state.add_tree_template(template);
+            }
+        }
+        for hole in holes {
+            self.write_node(hole)?;
+        }
+        Ok(true)
+    }
+}
+
+/// Finds the values of b which differ from a, as (position of the value in a, replacement value) in pre-order.
+/// Returns false if they differ in structure.
+fn diff_values(
+    state: &State,
+    a: NodeId,
+    b: NodeId,
+    position: &mut usize,
+    replacements: &mut Vec<(usize, NodeId)>,
+) -> bool {
+    if a == b {
+        *position += state.info(a).value_count;
+        return true;
+    }
+    match (state.node(a), state.node(b)) {
+        (Node::List(a), Node::List(b)) if a.len() == b.len() => a
+            .iter()
+            .zip(b)
+            .all(|(a, b)| diff_values(state, *a, *b, position, replacements)),
+        (Node::Value(_), Node::Value(_)) => {
+            replacements.push((*position, b));
+            *position += 1;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The subtree id with the values at the replacement positions (relative to position) replaced.
+fn patch_values(
+    state: &mut State,
+    id: NodeId,
+    position: &mut usize,
+    replacements: &mut std::iter::Peekable<std::vec::IntoIter<(usize, NodeId)>>,
+) -> NodeId {
+    let end = *position + state.info(id).value_count;
+    match replacements.peek() {
+        Some((next, _)) if *next < end => {}
+        _ => {
+            *position = end;
+            return id;
+        }
+    }
+    match state.node(id).clone() {
+        Node::List(children) => {
+            let children = children
+                .into_iter()
+                .map(|child| patch_values(state, child, position, replacements))
+                .collect();
+            state.intern(Node::List(children))
+        }
+        Node::Value(_) => {
+            *position += 1;
+            replacements.next().unwrap().1
+        }
+    }
+}
+
+/// The pattern matching both subtrees, which must have the same shape: values that differ are taken from the stream.
+fn merge_pattern(state: &State, a: NodeId, b: NodeId, size: &mut u32) -> BytePatternChild {
+    match (state.node(a), state.node(b)) {
+        (Node::List(a), Node::List(b)) => BytePatternChild::List(
+            a.iter()
+                .zip(b)
+                .map(|(a, b)| merge_pattern(state, *a, *b, size))
+                .collect(),
+        ),
+        (Node::Value(a), Node::Value(b)) if a == b => BytePatternChild::ConstantValue(a.clone()),
+        (Node::Value(a), Node::Value(_)) => {
+            let offset = *size;
+            *size += a.len() as u32;
+            BytePatternChild::ValueFromStreamAtOffset {
+                offset,
+                length: a.len() as u32,
+            }
+        }
+        _ => panic!("Subtrees must have the same shape"),
+    }
+}
+
+/// A pattern matching the subtree id (which must have the same shape as pattern) and everything pattern matches:
+/// constants which differ from id's values are taken from the stream.
+/// size is advanced past the values taken from the stream.
+fn generalize_pattern(
+    state: &State,
+    pattern: &BytePatternChild,
+    id: NodeId,
+    size: &mut u32,
+) -> BytePatternChild {
+    match (pattern, state.node(id)) {
+        (BytePatternChild::List(patterns), Node::List(children)) => BytePatternChild::List(
+            patterns
+                .iter()
+                .zip(children)
+                .map(|(pattern, child)| generalize_pattern(state, pattern, *child, size))
+                .collect(),
+        ),
+        (BytePatternChild::ConstantValue(constant), Node::Value(value)) if constant == value => {
+            BytePatternChild::ConstantValue(constant.clone())
+        }
+        (_, Node::Value(value)) => {
+            let offset = *size;
+            *size += value.len() as u32;
+            BytePatternChild::ValueFromStreamAtOffset {
+                offset,
+                length: value.len() as u32,
+            }
+        }
+        _ => panic!("Subtrees must have the same shape"),
+    }
+}
+
+/// The data stream for a use of pattern reproducing the subtree id, if it matches the pattern.
+fn node_byte_pattern_data(
+    state: &State,
+    id: NodeId,
+    pattern: &BytePatternTemplate,
+) -> Option<Vec<u8>> {
+    let mut data = vec![0; pattern.size as usize];
+    if fill_byte_pattern(state, id, &pattern.content, &mut data) {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+/// Writes the values of the subtree id which come from the stream into data,
+/// or returns false if the subtree does not match the pattern.
+fn fill_byte_pattern(
+    state: &State,
+    id: NodeId,
+    pattern: &BytePatternChild,
+    data: &mut [u8],
+) -> bool {
+    match (state.node(id), pattern) {
+        (Node::List(children), BytePatternChild::List(patterns)) => {
+            children.len() == patterns.len()
+                && children
+                    .iter()
+                    .zip(patterns)
+                    .all(|(child, pattern)| fill_byte_pattern(state, *child, pattern, data))
+        }
+        (Node::Value(value), BytePatternChild::ConstantValue(constant)) => value == constant,
+        (Node::Value(value), BytePatternChild::ValueFromStreamAtOffset { offset, length }) => {
+            if value.len() != *length as usize {
+                return false;
+            }
+            let offset = *offset as usize;
+            data[offset..offset + value.len()].copy_from_slice(value);
+            true
+        }
+        (_, BytePatternChild::TemplateUse(template_use)) => {
+            let offset = template_use.offset as usize;
+            let size = template_use.template.size as usize;
+            fill_byte_pattern(
+                state,
+                id,
+                &template_use.template.content,
+                &mut data[offset..offset + size],
+            )
+        }
+        _ => false,
+    }
+}
+
+/// Appends the offset and length of each value from the stream in pattern (in pre-order) to slots.
+/// offset is where pattern's data starts in the data stream.
+fn stream_slots(pattern: &BytePatternChild, offset: u32, slots: &mut Vec<(u32, u32)>) {
+    match pattern {
+        BytePatternChild::List(children) => {
+            for child in children {
+                stream_slots(child, offset, slots);
+            }
+        }
+        BytePatternChild::ConstantValue(_) => {}
+        BytePatternChild::ValueFromStreamAtOffset {
+            offset: value_offset,
+            length,
+        } => slots.push((offset + value_offset, *length)),
+        BytePatternChild::TemplateUse(template_use) => stream_slots(
+            &template_use.template.content,
+            offset + template_use.offset,
+            slots,
+        ),
+    }
+}
+
+/// A tree template matching only the subtree id.
+fn constant_tree_template(state: &State, id: NodeId) -> TreeTemplate {
+    match state.node(id) {
+        Node::List(children) => TreeTemplate::List(
+            children
+                .iter()
+                .map(|child| constant_tree_template(state, *child))
+                .collect(),
+        ),
+        Node::Value(value) => TreeTemplate::ConstantValue(value.clone()),
+    }
+}
+
+/// The most specific tree template matching both template and the subtree id.
+fn generalize_tree_template(template: &TreeTemplate, state: &State, id: NodeId) -> TreeTemplate {
+    match (template, state.node(id)) {
+        (TreeTemplate::List(templates), Node::List(children))
+            if templates.len() == children.len() =>
+        {
+            TreeTemplate::List(
+                templates
+                    .iter()
+                    .zip(children)
+                    .map(|(template, child)| generalize_tree_template(template, state, *child))
+                    .collect(),
+            )
+        }
+        (TreeTemplate::ConstantValue(constant), Node::Value(value)) if constant == value => {
+            TreeTemplate::ConstantValue(value.clone())
+        }
+        (TreeTemplate::ConstantValue(_), Node::Value(_))
+        | (TreeTemplate::ValueFromStream, Node::Value(_)) => TreeTemplate::ValueFromStream,
+        (TreeTemplate::TreeTemplateUse(template), _) => {
+            generalize_tree_template(template, state, id)
+        }
+        _ => TreeTemplate::TreeFromStream,
+    }
+}
+
+/// Checks the subtree id matches template, appending the subtrees which fill its holes (in pre-order) to holes.
+fn match_tree_template(
+    state: &State,
+    id: NodeId,
+    template: &TreeTemplate,
+    holes: &mut Vec<NodeId>,
+) -> bool {
+    match (state.node(id), template) {
+        (_, TreeTemplate::TreeFromStream) | (Node::Value(_), TreeTemplate::ValueFromStream) => {
+            holes.push(id);
+            true
+        }
+        (Node::Value(value), TreeTemplate::ConstantValue(constant)) => value == constant,
+        (Node::List(children), TreeTemplate::List(templates)) => {
+            children.len() == templates.len()
+                && children
+                    .iter()
+                    .zip(templates)
+                    .all(|(child, template)| match_tree_template(state, *child, template, holes))
+        }
+        (_, TreeTemplate::TreeTemplateUse(template)) => {
+            match_tree_template(state, id, template, holes)
+        }
+        // The encoder does not put byte patterns in tree templates
+        _ => false,
+    }
+}
+
+/// Approximate size of the parts of a tree template which are not holes, when written as nodes.
+/// use_size is the size of a TEMPLATE_USE, which recorded values are assumed to be written as.
+fn constant_size(template: &TreeTemplate, use_size: usize) -> usize {
+    match template {
+        TreeTemplate::List(children) => {
+            list_marker_size(children.len())
+                + children
+                    .iter()
+                    .map(|child| constant_size(child, use_size))
+                    .sum::<usize>()
+        }
+        TreeTemplate::ConstantValue(value) if is_recorded_value(value) => use_size,
+        TreeTemplate::ConstantValue(value) => value_marker_size(value.len()) + value.len(),
+        TreeTemplate::TreeTemplateUse(template) => constant_size(template, use_size),
+        TreeTemplate::ValueFromStream
+        | TreeTemplate::TreeFromStream
+        | TreeTemplate::BytePatternTemplateUse(_) => 0,
+    }
+}
+
+/// Lists within the template which are themselves tree templates are written as TEMPLATE_USEs.
+fn write_tree_template<W: Write>(
+    state: &State,
+    out: &mut W,
+    template: &TreeTemplate,
+    root: bool,
+) -> io::Result<()> {
+    match template {
+        TreeTemplate::List(children) => match state.tree_template_index(template) {
+            Some(index) if !root => write_template_use(out, index),
+            _ => {
+                write_list_marker(out, children.len())?;
+                for child in children {
+                    write_tree_template(state, out, child, false)?;
+                }
+                Ok(())
+            }
+        },
+        TreeTemplate::ConstantValue(value) => write_value(out, value),
+        TreeTemplate::ValueFromStream => out.write_u8(STREAM_VALUE_MARKER),
+        TreeTemplate::TreeFromStream => out.write_u8(STREAM_TREE_MARKER),
+        TreeTemplate::TreeTemplateUse(template) => {
+            let index = state
+                .tree_template_index(template)
+                .expect("Tree templates can only use previous templates");
+            write_template_use(out, index)
+        }
+        TreeTemplate::BytePatternTemplateUse(template) => {
+            let index = state
+                .byte_pattern_index(template)
+                .expect("Tree templates can only use previous templates");
+            write_template_use(out, index)
+        }
+    }
+}
+
+fn write_byte_pattern<W: Write>(
+    state: &State,
+    out: &mut W,
+    pattern: &BytePatternChild,
+) -> io::Result<()> {
+    match pattern {
+        BytePatternChild::List(children) => {
+            write_list_marker(out, children.len())?;
+            for child in children {
+                write_byte_pattern(state, out, child)?;
+            }
+            Ok(())
+        }
+        BytePatternChild::ConstantValue(value) => write_value(out, value),
+        BytePatternChild::ValueFromStreamAtOffset { offset, length } => {
+            out.write_u8(STREAM_VALUE_MARKER)?;
+            write_varint(out, *offset as u64)?;
+            write_varint(out, *length as u64)
+        }
+        BytePatternChild::TemplateUse(template_use) => {
+            let index = state
+                .byte_pattern_index(&template_use.template)
+                .expect("Byte pattern templates can only use previous templates");
+            write_template_use(out, index)?;
+            write_varint(out, template_use.offset as u64)
+        }
+    }
+}
+
+/// Nodes any document may expand to (see State::budget), in addition to EXPANSION_PER_BYTE for each of its bytes.
+/// Sequences and nested template uses let a few bytes stand for many nodes, so this bounds them rather than the input.
+const EXPANSION_LIMIT: usize = 1 << 22;
+const EXPANSION_PER_BYTE: usize = 64;
+
+/// Decodes the tree making up the rest of a document, failing with io::ErrorKind::InvalidData
+/// if it expands to more than EXPANSION_LIMIT nodes (plus EXPANSION_PER_BYTE for each byte of the document).
+fn decode_compressed_document(state: &mut State, rdr: &mut Cursor<&[u8]>) -> io::Result<NodeId> {
+    state.budget =
+        EXPANSION_LIMIT.saturating_add(rdr.get_ref().len().saturating_mul(EXPANSION_PER_BYTE));
+    prefix_decode_compressed(state, rdr)
+}
+
+fn prefix_decode_compressed<T: ReadBytesExt>(
+    state: &mut State,
+    input: &mut T,
+) -> io::Result<NodeId> {
+    let marker = try_read_marker(input)?;
+    decode_compressed_node(state, marker, input)
+}
+
+/// Checks a run of repeat template uses fits in the remaining length of its list.
+fn check_repeat(repeat: usize, remaining: usize) -> io::Result<()> {
+    if repeat <= remaining {
+        Ok(())
+    } else {
+        Err(invalid_data(
+            "Template sequence extends past the end of its list".to_string(),
+        ))
+    }
+}
+
+fn decode_compressed_node<T: ReadBytesExt>(
+    state: &mut State,
+    marker: Marker,
+    input: &mut T,
+) -> io::Result<NodeId> {
+    Ok(match marker {
+        Marker::List(count) => {
             let mut children = vec![];
+            while children.len() < count {
+                match try_read_marker(input)? {
+                    Marker::Other(TEMPLATE_USE_SEQUENCE_MARKER) => {
+                        let index = read_varint_u32(input)?;
+                        let repeat = read_varint_u32(input)? as usize;
+                        check_repeat(repeat, count - children.len())?;
+                        state.charge(repeat)?;
+                        for _i in 0..repeat {
+                            children.push(decode_template_use(state, index, input)?);
+                        }
+                    }
+                    Marker::Other(TEMPLATE_USE_COLUMNS_MARKER) => {
+                        let index = read_varint_u32(input)?;
+                        let repeat = read_varint_u32(input)? as usize;
+                        check_repeat(repeat, count - children.len())?;
+                        state.charge(repeat)?;
+                        decode_template_use_columns(state, index, repeat, input, &mut children)?;
+                    }
+                    Marker::Other(BYTE_PATTERN_TEMPLATE_SEQUENCE_MARKER) => {
+                        let size = read_varint_u32(input)?;
+                        let content = read_byte_pattern(state, size, input)?;
+                        let index =
+                            state.add_byte_pattern(Rc::new(BytePatternTemplate { size, content }));
+                        let repeat = read_varint_u32(input)? as usize;
+                        check_repeat(repeat, count - children.len())?;
+                        state.charge(repeat)?;
+                        let padding = read_varint(input)?;
+                        if io::copy(&mut input.take(padding), &mut io::sink())? != padding {
+                            return Err(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                "Padding extends past the end of the data",
+                            ));
+                        }
+                        for _i in 0..repeat {
+                            children.push(decode_template_use(state, index, input)?);
+                        }
+                    }
+                    marker => children.push(decode_compressed_node(state, marker, input)?),
+                }
+            }
+            let id = state.intern(Node::List(children));
+            state.record(id);
+            id
+        }
+        Marker::Value(value) => {
+            let recorded = is_recorded_value(&value);
+            let id = state.intern(Node::Value(value));
+            if recorded {
+                state.record(id);
+            }
+            id
+        }
+        Marker::Other(TEMPLATE_USE_MARKER) => {
+            let index = read_varint_u32(input)?;
+            decode_template_use(state, index, input)?
+        }
+        Marker::Other(BYTE_PATTERN_TEMPLATE_MARKER) => {
+            let size = read_varint_u32(input)?;
+            let content = read_byte_pattern(state, size, input)?;
+            let index = state.add_byte_pattern(Rc::new(BytePatternTemplate { size, content }));
+            decode_template_use(state, index, input)?
+        }
+        Marker::Other(TREE_TEMPLATE_MARKER) => {
+            let template = read_tree_template(state, input)?;
+            let index = state.add_tree_template(Rc::new(template));
+            decode_template_use(state, index, input)?
+        }
+        Marker::Other(TEMPLATE_USE_PATCHED_MARKER) => {
+            let index = read_varint_u32(input)?;
+            let base = match state.template(index) {
+                Some(Template::Subtree(id)) => *id,
+                _ => return Err(invalid_data(format!("Template {} is not a subtree", index))),
+            };
+            let count = read_varint(input)?;
+            let mut replacements = vec![];
+            let mut next = 0usize;
             for _i in 0..count {
-                children.push(prefix_decode_compressed(state, input));
+                let position = next.checked_add(read_varint(input)? as usize);
+                let position = match position {
+                    Some(position) if position < state.info(base).value_count => position,
+                    _ => {
+                        return Err(invalid_data(format!(
+                            "Replacement past the end of template {}",
+                            index
+                        )))
+                    }
+                };
+                let value = prefix_decode_compressed(state, input)?;
+                if !matches!(state.node(value), Node::Value(_)) {
+                    return Err(invalid_data("Replacements must be values".to_string()));
+                }
+                replacements.push((position, value));
+                next = position + 1;
             }
-            let out = Concrete::List(children);
-            state.record(&out);
-            out
+            let id = patch_values(
+                state,
+                base,
+                &mut 0,
+                &mut replacements.into_iter().peekable(),
+            );
+            state.record(id);
+            id
+        }
+        Marker::Other(marker) => return Err(invalid_data(format!("Invalid marker {}", marker))),
+    })
+}
+
+/// Decodes a use of the template at index, which is followed by its data stream or tree stream (if any).
+fn decode_template_use<T: ReadBytesExt>(
+    state: &mut State,
+    index: u32,
+    input: &mut T,
+) -> io::Result<NodeId> {
+    let id = match state.template(index) {
+        Some(Template::Subtree(id)) => return Ok(*id),
+        Some(Template::BytePattern(pattern, expansion)) => {
+            let (size, expansion) = (pattern.size, expansion.clone());
+            read_byte_pattern_use(state, size, &expansion, input)?
+        }
+        Some(Template::Tree(_, expansion)) => {
+            let expansion = expansion.clone();
+            expand_tree_template(state, &expansion, input)?
+        }
+        None => return Err(invalid_data(format!("Template {} does not exist", index))),
+    };
+    state.record(id);
+    Ok(id)
+}
+
+/// Decodes repeat uses of the byte pattern template at index from their data streams, appending them to children.
+fn decode_template_use_columns<T: ReadBytesExt>(
+    state: &mut State,
+    index: u32,
+    repeat: usize,
+    input: &mut T,
+    children: &mut Vec<NodeId>,
+) -> io::Result<()> {
+    let (pattern, expansion) = match state.template(index) {
+        Some(Template::BytePattern(pattern, expansion)) => (pattern.clone(), expansion.clone()),
+        _ => {
+            return Err(invalid_data(format!(
+                "Template {} is not a byte pattern template",
+                index
+            )))
+        }
+    };
+    let mut slots = vec![];
+    stream_slots(&pattern.content, 0, &mut slots);
+    let columns = slots
+        .iter()
+        .map(|(_, length)| {
+            let column_size = repeat
+                .checked_mul(*length as usize)
+                .ok_or_else(|| invalid_data("Column is too large".to_string()))?;
+            read_data(input, column_size)
+        })
+        .collect::<io::Result<Vec<Vec<u8>>>>()?;
+    for i in 0..repeat {
+        let mut slot = 0;
+        let id = expand_byte_pattern(state, &expansion, &mut |_, length| {
+            let length = length as usize;
+            let value = columns[slot][i * length..(i + 1) * length].to_vec();
+            slot += 1;
+            value
+        });
+        state.record(id);
+        children.push(id);
+    }
+    Ok(())
+}
+
+fn read_byte_pattern_use<T: ReadBytesExt>(
+    state: &mut State,
+    size: u32,
+    expansion: &Expansion,
+    input: &mut T,
+) -> io::Result<NodeId> {
+    let data = read_data(input, size as usize)?;
+    Ok(expand_byte_pattern(
+        state,
+        expansion,
+        &mut |offset, length| data[offset as usize..(offset + length) as usize].to_vec(),
+    ))
+}
+
+fn read_tree_template<T: ReadBytesExt>(
+    state: &mut State,
+    input: &mut T,
+) -> io::Result<TreeTemplate> {
+    let marker = try_read_marker(input)?;
+    tree_template_from_marker(state, marker, input)
+}
+
+fn tree_template_from_marker<T: ReadBytesExt>(
+    state: &mut State,
+    marker: Marker,
+    input: &mut T,
+) -> io::Result<TreeTemplate> {
+    Ok(match marker {
+        Marker::List(count) => {
+            let mut children = vec![];
+            while children.len() < count {
+                match try_read_marker(input)? {
+                    Marker::Other(TEMPLATE_USE_SEQUENCE_MARKER) => {
+                        let index = read_varint_u32(input)?;
+                        let repeat = read_varint_u32(input)? as usize;
+                        check_repeat(repeat, count - children.len())?;
+                        let template = tree_template_use(state, index, repeat)?;
+                        for _i in 0..repeat {
+                            children.push(template.clone());
+                        }
+                    }
+                    marker => children.push(tree_template_from_marker(state, marker, input)?),
+                }
+            }
+            TreeTemplate::List(children)
+        }
+        Marker::Value(value) => TreeTemplate::ConstantValue(value),
+        Marker::Other(STREAM_VALUE_MARKER) => TreeTemplate::ValueFromStream,
+        Marker::Other(STREAM_TREE_MARKER) => TreeTemplate::TreeFromStream,
+        Marker::Other(TEMPLATE_USE_MARKER) => {
+            let index = read_varint_u32(input)?;
+            tree_template_use(state, index, 1)?
         }
-        Marker::Value(value) => Concrete::Value(value),
         Marker::Other(marker) => {
-            if marker == TEMPLATE_USE_MARKER {
-                let index = input.read_u32::<LittleEndian>().unwrap();
-                state.templates[index as usize].clone()
-            } else {
-                panic!()
+            return Err(invalid_data(format!(
+                "Invalid tree template marker {}",
+                marker
+            )))
+        }
+    })
+}
+
+/// The tree template for repeat uses of the template at index within a tree template,
+/// charging what they expand to (see State::budget).
+fn tree_template_use(state: &mut State, index: u32, repeat: usize) -> io::Result<TreeTemplate> {
+    let limit = state.budget;
+    let (template, size) = match state.template(index) {
+        Some(Template::Subtree(id)) => {
+            // Only built once it is known to fit, as shared subtrees can make it far larger than the document.
+            let id = *id;
+            let size = subtree_size(state, id, limit);
+            state.charge(size.saturating_mul(repeat))?;
+            return Ok(constant_tree_template(state, id));
+        }
+        Some(Template::Tree(template, _)) => (
+            TreeTemplate::TreeTemplateUse(template.clone()),
+            tree_template_size(template, limit),
+        ),
+        Some(Template::BytePattern(pattern, _)) => (
+            TreeTemplate::BytePatternTemplateUse(pattern.clone()),
+            byte_pattern_size(&pattern.content, limit),
+        ),
+        None => return Err(invalid_data(format!("Template {} does not exist", index))),
+    };
+    state.charge(size.saturating_mul(repeat))?;
+    Ok(template)
+}
+
+/// 1 (for the list) plus the sizes of children, stopping once that is more than limit.
+fn list_size<C>(children: &[C], limit: usize, size: impl Fn(&C, usize) -> usize) -> usize {
+    let mut total = 1usize;
+    for child in children {
+        if total > limit {
+            break;
+        }
+        total = total.saturating_add(size(child, limit - total));
+    }
+    total
+}
+
+/// The number of nodes in the subtree id, counting shared subtrees each time they appear, or more than limit if that is larger.
+fn subtree_size(state: &State, id: NodeId, limit: usize) -> usize {
+    match state.node(id) {
+        Node::List(children) => list_size(children, limit, |child, limit| {
+            subtree_size(state, *child, limit)
+        }),
+        Node::Value(_) => 1,
+    }
+}
+
+/// The number of nodes in template's expansion (see tree_template_expansion), or more than limit if that is larger.
+fn tree_template_size(template: &TreeTemplate, limit: usize) -> usize {
+    match template {
+        TreeTemplate::List(children) => list_size(children, limit, tree_template_size),
+        TreeTemplate::TreeTemplateUse(template) => tree_template_size(template, limit),
+        TreeTemplate::BytePatternTemplateUse(pattern) => byte_pattern_size(&pattern.content, limit),
+        _ => 1,
+    }
+}
+
+/// The number of nodes in pattern's expansion (see byte_pattern_expansion), or more than limit if that is larger.
+fn byte_pattern_size(pattern: &BytePatternChild, limit: usize) -> usize {
+    match pattern {
+        BytePatternChild::List(children) => list_size(children, limit, byte_pattern_size),
+        BytePatternChild::TemplateUse(template_use) => {
+            byte_pattern_size(&template_use.template.content, limit)
+        }
+        _ => 1,
+    }
+}
+
+/// Expands a tree template's expansion (see tree_template_expansion), reading its holes from input.
+fn expand_tree_template<T: ReadBytesExt>(
+    state: &mut State,
+    expansion: &Expansion,
+    input: &mut T,
+) -> io::Result<NodeId> {
+    Ok(match expansion {
+        Expansion::Constant(id) => *id,
+        Expansion::List(children) => {
+            let children = children
+                .iter()
+                .map(|child| expand_tree_template(state, child, input))
+                .collect::<io::Result<Vec<NodeId>>>()?;
+            state.intern(Node::List(children))
+        }
+        Expansion::StreamValue => {
+            let id = prefix_decode_compressed(state, input)?;
+            match state.node(id) {
+                Node::Value(_) => id,
+                Node::List(_) => {
+                    return Err(invalid_data("Tree template expected a value".to_string()))
+                }
+            }
+        }
+        Expansion::StreamTree => prefix_decode_compressed(state, input)?,
+        Expansion::BytePattern { size, content } => {
+            read_byte_pattern_use(state, *size, content, input)?
+        }
+        Expansion::DataValue { .. } => unreachable!("Tree templates have no data stream"),
+    })
+}
+
+/// Prepares a tree template for expanding: see Expansion.
+fn tree_template_expansion(state: &mut State, template: &TreeTemplate) -> Expansion {
+    match template {
+        TreeTemplate::List(children) => {
+            let children = children
+                .iter()
+                .map(|child| tree_template_expansion(state, child))
+                .collect();
+            Expansion::list(state, children)
+        }
+        TreeTemplate::ConstantValue(value) => {
+            Expansion::Constant(state.intern(Node::Value(value.clone())))
+        }
+        TreeTemplate::ValueFromStream => Expansion::StreamValue,
+        TreeTemplate::TreeFromStream => Expansion::StreamTree,
+        TreeTemplate::TreeTemplateUse(template) => tree_template_expansion(state, template),
+        TreeTemplate::BytePatternTemplateUse(pattern) => Expansion::BytePattern {
+            size: pattern.size,
+            content: Rc::new(byte_pattern_expansion(state, &pattern.content, 0)),
+        },
+    }
+}
+
+/// Reads a pattern for a BytePatternTemplate with the given size.
+fn read_byte_pattern<T: ReadBytesExt>(
+    state: &mut State,
+    size: u32,
+    input: &mut T,
+) -> io::Result<BytePatternChild> {
+    let marker = try_read_marker(input)?;
+    byte_pattern_from_marker(state, size, marker, input)
+}
+
+fn byte_pattern_from_marker<T: ReadBytesExt>(
+    state: &mut State,
+    size: u32,
+    marker: Marker,
+    input: &mut T,
+) -> io::Result<BytePatternChild> {
+    // u32 offsets and lengths can not overflow in u64.
+    let check_range = |offset: u64, length: u64| {
+        if offset + length <= size as u64 {
+            Ok(())
+        } else {
+            Err(invalid_data("Byte pattern reads past its size".to_string()))
+        }
+    };
+    Ok(match marker {
+        Marker::List(count) => {
+            let mut children = vec![];
+            while children.len() < count {
+                match try_read_marker(input)? {
+                    Marker::Other(TEMPLATE_USE_SEQUENCE_MARKER) => {
+                        let template = byte_pattern_template(state, input)?;
+                        let repeat = read_varint_u32(input)?;
+                        let offset = read_varint_u32(input)?;
+                        if repeat as usize > count - children.len() {
+                            return Err(invalid_data(
+                                "Template sequence extends past the end of its list".to_string(),
+                            ));
+                        }
+                        check_range(offset as u64, repeat as u64 * template.size as u64)?;
+                        let size = byte_pattern_size(&template.content, state.budget);
+                        state.charge(size.saturating_mul(repeat as usize))?;
+                        for i in 0..repeat {
+                            children.push(BytePatternChild::TemplateUse(OffsetTemplateUse {
+                                template: template.clone(),
+                                offset: offset + i * template.size,
+                            }));
+                        }
+                    }
+                    marker => children.push(byte_pattern_from_marker(state, size, marker, input)?),
+                }
+            }
+            BytePatternChild::List(children)
+        }
+        Marker::Value(value) => BytePatternChild::ConstantValue(value),
+        Marker::Other(STREAM_VALUE_MARKER) => {
+            let offset = read_varint_u32(input)?;
+            let length = read_varint_u32(input)?;
+            check_range(offset as u64, length as u64)?;
+            BytePatternChild::ValueFromStreamAtOffset { offset, length }
+        }
+        Marker::Other(TEMPLATE_USE_MARKER) => {
+            let template = byte_pattern_template(state, input)?;
+            let offset = read_varint_u32(input)?;
+            check_range(offset as u64, template.size as u64)?;
+            let size = byte_pattern_size(&template.content, state.budget);
+            state.charge(size)?;
+            BytePatternChild::TemplateUse(OffsetTemplateUse { template, offset })
+        }
+        Marker::Other(marker) => {
+            return Err(invalid_data(format!(
+                "Invalid byte pattern marker {}",
+                marker
+            )))
+        }
+    })
+}
+
+/// Reads the index of a byte pattern template.
+fn byte_pattern_template<T: ReadBytesExt>(
+    state: &State,
+    input: &mut T,
+) -> io::Result<Rc<BytePatternTemplate>> {
+    let index = read_varint_u32(input)?;
+    match state.template(index) {
+        Some(Template::BytePattern(template, _)) => Ok(template.clone()),
+        _ => Err(invalid_data(format!(
+            "Template {} is not a byte pattern template",
+            index
+        ))),
+    }
+}
+
+/// Expands a byte pattern's expansion (see byte_pattern_expansion).
+/// Values from the data stream are taken from read(offset, length), in pre-order.
+fn expand_byte_pattern<F: FnMut(u32, u32) -> Vec<u8>>(
+    state: &mut State,
+    expansion: &Expansion,
+    read: &mut F,
+) -> NodeId {
+    match expansion {
+        Expansion::Constant(id) => *id,
+        Expansion::List(children) => {
+            let children = children
+                .iter()
+                .map(|child| expand_byte_pattern(state, child, read))
+                .collect();
+            state.intern(Node::List(children))
+        }
+        Expansion::DataValue { offset, length } => {
+            let value = read(*offset, *length);
+            state.intern(Node::Value(value))
+        }
+        _ => unreachable!("Byte patterns only take values from their data stream"),
+    }
+}
+
+/// Prepares a byte pattern (whose data starts at offset in the data stream) for expanding: see Expansion.
+fn byte_pattern_expansion(state: &mut State, pattern: &BytePatternChild, offset: u32) -> Expansion {
+    match pattern {
+        BytePatternChild::List(children) => {
+            let children = children
+                .iter()
+                .map(|child| byte_pattern_expansion(state, child, offset))
+                .collect();
+            Expansion::list(state, children)
+        }
+        BytePatternChild::ConstantValue(value) => {
+            Expansion::Constant(state.intern(Node::Value(value.clone())))
+        }
+        BytePatternChild::ValueFromStreamAtOffset {
+            offset: value_offset,
+            length,
+        } => Expansion::DataValue {
+            offset: offset + value_offset,
+            length: *length,
+        },
+        BytePatternChild::TemplateUse(template_use) => byte_pattern_expansion(
+            state,
+            &template_use.template.content,
+            offset + template_use.offset,
+        ),
+    }
+}
+
+/// The byte pattern for T, which is checked to match T's layout.
+fn plain_data_pattern<T: PlainData>() -> BytePatternTemplate {
+    let pattern = schema_byte_pattern(&T::schema()).expect("PlainData must have a fixed size");
+    let mut slots = vec![];
+    stream_slots(&pattern.content, 0, &mut slots);
+    let offsets: Vec<usize> = slots.iter().map(|(offset, _)| *offset as usize).collect();
+    assert!(
+        pattern.size as usize == std::mem::size_of::<T>() && offsets == T::terminal_offsets(),
+        "PlainData layout does not match its byte pattern"
+    );
+    pattern
+}
+
+/// PlainData is copied as is, and the format is little endian, so other platforms would silently write and read the wrong values.
+fn check_plain_data_platform() -> io::Result<()> {
+    if cfg!(target_endian = "little") {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "PlainData is only supported on little endian platforms",
+        ))
+    }
+}
+
+/// If offset is a multiple of T's alignment (which is always a power of two).
+fn is_aligned_for<T>(offset: usize) -> bool {
+    offset & (std::mem::align_of::<T>() - 1) == 0
+}
+
+/// Writes a PrefixCompressedEncoding document holding a list of values, using a BYTE_PATTERN_TEMPLATE_SEQUENCE
+/// whose data stream is a copy of values' memory.
+/// The data stream is aligned for T relative to the start of the document, so read_plain_data can decode it in place.
+/// The padding only accounts for the document itself: when it is embedded in other data (for example after a header),
+/// it must start at an offset aligned for T, or read_plain_data fails with io::ErrorKind::InvalidInput.
+///
+/// Fails with io::ErrorKind::Unsupported on big endian platforms.
+pub fn write_plain_data<T: PlainData, W: Write>(values: &[T], out: &mut W) -> io::Result<()> {
+    check_plain_data_platform()?;
+    let mut header = vec![];
+    write_list_marker(&mut header, values.len())?;
+    if !values.is_empty() {
+        let pattern = plain_data_pattern::<T>();
+        header.push(BYTE_PATTERN_TEMPLATE_SEQUENCE_MARKER);
+        write_varint(&mut header, pattern.size as u64)?;
+        write_byte_pattern(&State::new(), &mut header, &pattern.content)?;
+        write_varint(&mut header, values.len() as u64)?;
+        let mut padding = 0;
+        while !is_aligned_for::<T>(header.len() + varint_size(padding as u64) + padding) {
+            padding += 1;
+        }
+        write_varint(&mut header, padding as u64)?;
+        header.resize(header.len() + padding, 0);
+    }
+    out.write_all(&header)?;
+    // Safety: PlainData has no padding, so all of its bytes are initialized.
+    let data = unsafe {
+        std::slice::from_raw_parts(values.as_ptr() as *const u8, std::mem::size_of_val(values))
+    };
+    out.write_all(data)
+}
+
+/// Decodes a document written by write_plain_data in place.
+///
+/// Fails with io::ErrorKind::InvalidData if data is not a list of T written by write_plain_data,
+/// or io::ErrorKind::InvalidInput if the data stream is not aligned for T in memory
+/// (which can be fixed by copying the document to a buffer aligned for T).
+/// Fails with io::ErrorKind::Unsupported on big endian platforms.
+pub fn read_plain_data<T: PlainData>(data: &[u8]) -> io::Result<&[T]> {
+    check_plain_data_platform()?;
+    let invalid = |message: &str| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            message.to_string(),
+        ))
+    };
+    let mut input = Cursor::new(data);
+    let count = match try_read_marker(&mut input)? {
+        Marker::List(count) => count,
+        _ => return invalid("Document is not a list"),
+    };
+    if count > 0 {
+        if input.read_u8()? != BYTE_PATTERN_TEMPLATE_SEQUENCE_MARKER {
+            return invalid("Document is not a BYTE_PATTERN_TEMPLATE_SEQUENCE");
+        }
+        let size = read_varint_u32(&mut input)?;
+        let content = read_byte_pattern(&mut State::new(), size, &mut input)?;
+        if (BytePatternTemplate { size, content }) != plain_data_pattern::<T>() {
+            return invalid("Byte pattern does not match the type");
+        }
+        if read_varint(&mut input)? != count as u64 {
+            return invalid("Sequence is not the whole list");
+        }
+        let padding = read_varint(&mut input)?;
+        match input.position().checked_add(padding) {
+            Some(start) if start <= data.len() as u64 => input.set_position(start),
+            _ => return invalid("Padding extends past the end of the document"),
+        }
+    }
+    let stream = &data[input.position() as usize..];
+    if Some(stream.len()) != count.checked_mul(std::mem::size_of::<T>()) {
+        return invalid("Data stream does not hold the list");
+    }
+    if !is_aligned_for::<T>(stream.as_ptr() as usize) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Data stream is not aligned for the type",
+        ));
+    }
+    // Safety: the pointer is aligned, the length is checked, and PlainData is valid for every bit pattern.
+    Ok(unsafe { std::slice::from_raw_parts(stream.as_ptr() as *const T, count) })
+}
+
+/// View of an interned subtree.
+struct NodeView<'a> {
+    state: &'a State,
+    id: NodeId,
+}
+
+impl<'a> View for NodeView<'a> {
+    type Value = Vec<u8>;
+    fn visit<V: Visitor<Value = Vec<u8>>>(&self, v: &mut V) {
+        match self.state.node(self.id) {
+            Node::List(children) => {
+                for child in children {
+                    v.visit_list(&NodeView {
+                        state: self.state,
+                        id: *child,
+                    });
+                }
             }
+            Node::Value(value) => v.visit_value(value.clone()),
         }
     }
 }
@@ -0,0 +1,323 @@
+//! Property based tests for the encodings.
+//!
+//! Generates arbitrary leaf trees and typed value trees, then checks that every Encoder/Decoder pair
+//! round trips them, that all encodings decode to the same tree, and that TypeViewer output is stable.
+
+use super::basic_encoding::BasicEncoding;
+use super::container::{open, serialize_document, FLAG_CHECKSUM};
+use super::data_models::leaf_tree::concrete::{view_to_concrete, Concrete};
+use super::data_models::leaf_tree::{View, Visitor};
+use super::data_models::typed_value_tree::concrete as typed;
+use super::encoding::*;
+use super::incremental_decoding::visit_read;
+use super::prefix_encoding::{
+    CompressionOptions, PrefixCompressedEncoding, PrefixDictionaryEncoding, PrefixEncoding,
+    PrefixSizedEncoding, PrefixTunedEncoding, PrefixWindowedEncoding, TemplateDictionary,
+};
+use super::type_to_leaf::TypeViewer;
+use proptest::collection::{hash_map, vec};
+use proptest::prelude::*;
+use std::io::{self, Read};
+use std::num::NonZeroU32;
+use std::rc::Rc;
+
+/// Bytes are biased toward a few small numbers so that duplicate subtrees (and thus templates) are common.
+fn arb_byte() -> impl Strategy<Value = u8> {
+    prop_oneof![0u8..3, any::<u8>()]
+}
+
+/// Mostly single bytes, but covering every value length encoding.
+fn arb_value() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        4 => vec(arb_byte(), 1..2),
+        2 => vec(arb_byte(), 0..5),
+        1 => vec(arb_byte(), 60..70),
+    ]
+}
+
+pub fn arb_leaf_tree() -> impl Strategy<Value = Concrete<Vec<u8>>> {
+    let leaf = prop_oneof![
+        arb_value().prop_map(Concrete::Value),
+        Just(Concrete::List(vec![])),
+    ];
+    leaf.prop_recursive(6, 256, 8, |inner| vec(inner, 0..8).prop_map(Concrete::List))
+}
+
+fn arb_id() -> impl Strategy<Value = u128> {
+    prop_oneof![0u128..4, any::<u128>()]
+}
+
+pub fn arb_typed_tree() -> impl Strategy<Value = typed::Concrete<u128>> {
+    let leaf = (arb_id(), vec(arb_byte(), 0..6)).prop_map(|(type_name, bytes)| typed::Concrete {
+        type_name,
+        content: typed::StructOrValue::Value(bytes),
+    });
+    leaf.prop_recursive(4, 64, 4, |inner| {
+        (arb_id(), hash_map(arb_id(), vec(inner, 0..3), 0..4)).prop_map(|(type_name, map)| {
+            typed::Concrete {
+                type_name,
+                content: typed::StructOrValue::Struct(map),
+            }
+        })
+    })
+}
+
+fn decode<T: Decoder<Value = Vec<u8>>>(decoder: T, data: Vec<u8>) -> Concrete<Vec<u8>> {
+    view_to_concrete(&EncodedLeafTree { decoder, data })
+}
+
+fn check_round_trip<T, TView>(e: T, view: &TView, expected: &Concrete<Vec<u8>>)
+where
+    T: Encoder<Value = Vec<u8>> + Decoder<Value = Vec<u8>>,
+    TView: View<Value = Vec<u8>>,
+{
+    let encoded = e.serialize(view);
+    let decoded_view = EncodedLeafTree {
+        decoder: e,
+        data: encoded,
+    };
+    assert_eq!(&view_to_concrete(&decoded_view), expected, "decode");
+
+    // Encoding is a function of the tree, so re-encoding the decoded tree must reproduce the same bytes.
+    let encoded2 = decoded_view.decoder.serialize(&decoded_view);
+    assert_eq!(&decoded_view.data, &encoded2, "re-encode");
+}
+
+fn check_all_encodings<TView: View<Value = Vec<u8>>>(view: &TView) {
+    let c = view_to_concrete(view);
+    check_round_trip(BasicEncoding, view, &c);
+    check_round_trip(PrefixEncoding, view, &c);
+    check_round_trip(PrefixCompressedEncoding, view, &c);
+    check_round_trip(PrefixSizedEncoding, view, &c);
+
+    // All encodings must agree on the decoded tree
+    let basic = decode(BasicEncoding, BasicEncoding.serialize(view));
+    let prefix = decode(PrefixEncoding, PrefixEncoding.serialize(view));
+    let compressed = decode(
+        PrefixCompressedEncoding,
+        PrefixCompressedEncoding.serialize(view),
+    );
+    assert_eq!(basic, prefix, "basic vs prefix");
+    assert_eq!(prefix, compressed, "prefix vs compressed");
+    let sized = decode(PrefixSizedEncoding, PrefixSizedEncoding.serialize(view));
+    assert_eq!(prefix, sized, "prefix vs sized");
+}
+
+/// Checks incremental decoding, with the input split into small chunks, produces the same tree.
+fn check_incremental<T>(e: T, c: &Concrete<Vec<u8>>)
+where
+    T: Encoder<Value = Vec<u8>> + IncrementalDecoder<Value = Vec<u8>>,
+{
+    struct Chunks<'a>(&'a [u8]);
+    impl<'a> Read for Chunks<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = buf.len().min(self.0.len()).min(3);
+            buf[..count].copy_from_slice(&self.0[..count]);
+            self.0 = &self.0[count..];
+            Ok(count)
+        }
+    }
+
+    struct Collect(Concrete<Vec<u8>>);
+    impl Visitor for Collect {
+        type Value = Vec<u8>;
+        fn visit_list<T: View<Value = Vec<u8>>>(&mut self, t: &T) {
+            if let Concrete::List(list) = &mut self.0 {
+                list.push(view_to_concrete(t));
+            }
+        }
+        fn visit_value(&mut self, value: Vec<u8>) {
+            self.0 = Concrete::Value(value);
+        }
+    }
+
+    let encoded = e.serialize(c);
+    let mut out = Collect(Concrete::List(vec![]));
+    visit_read(e, Chunks(&encoded), &mut out).unwrap();
+    assert_eq!(&out.0, c, "incremental");
+}
+
+/// Checks the layout documented in type_to_leaf.
+fn check_typed_value_layout(c: &Concrete<Vec<u8>>, t: &typed::Concrete<u128>) {
+    fn bytes_of(c: &Concrete<Vec<u8>>) -> &[u8] {
+        match c {
+            Concrete::Value(v) => v,
+            Concrete::List(_) => panic!("bytes must be a value"),
+        }
+    }
+
+    let (type_name, content) = match c {
+        Concrete::List(list) if list.len() == 2 => (&list[0], &list[1]),
+        _ => panic!("TypedValue must be a list of type name and content"),
+    };
+    assert_eq!(bytes_of(type_name), &t.type_name.to_le_bytes());
+
+    match &t.content {
+        typed::StructOrValue::Value(bytes) => {
+            assert_eq!(bytes_of(content), bytes.as_slice());
+        }
+        typed::StructOrValue::Struct(map) => {
+            let entries = match content {
+                Concrete::List(entries) => entries,
+                Concrete::Value(_) => panic!("struct content must be a list"),
+            };
+            // Each map entry is a name followed by its children list
+            assert_eq!(entries.len(), map.len() * 2);
+            for entry in entries.chunks(2) {
+                let (name, children) = (&entry[0], &entry[1]);
+                let mut name_bytes = [0u8; 16];
+                name_bytes.copy_from_slice(bytes_of(name));
+                let expected_children = &map[&u128::from_le_bytes(name_bytes)];
+                match children {
+                    Concrete::List(list) => {
+                        assert_eq!(list.len(), expected_children.len());
+                        for (child, expected) in list.iter().zip(expected_children) {
+                            check_typed_value_layout(child, expected);
+                        }
+                    }
+                    Concrete::Value(_) => panic!("children must be a list"),
+                }
+            }
+        }
+    }
+}
+
+/// A TypedValue (in the layout documented in type_to_leaf) with its map entries sorted by name, recursively.
+fn sort_entries(c: &Concrete<Vec<u8>>) -> Concrete<Vec<u8>> {
+    let (type_name, content) = match c {
+        Concrete::List(list) => (&list[0], &list[1]),
+        Concrete::Value(_) => panic!("TypedValue must be a list"),
+    };
+    let content = match content {
+        Concrete::List(entries) => {
+            let mut entries: Vec<_> = entries
+                .chunks(2)
+                .map(|entry| {
+                    let children = match &entry[1] {
+                        Concrete::List(children) => children.iter().map(sort_entries).collect(),
+                        Concrete::Value(_) => panic!("children must be a list"),
+                    };
+                    (entry[0].clone(), Concrete::List(children))
+                })
+                .collect();
+            entries.sort_by_key(|(name, _)| match name {
+                Concrete::Value(name) => name.clone(),
+                Concrete::List(_) => panic!("name must be a value"),
+            });
+            Concrete::List(
+                entries
+                    .into_iter()
+                    .flat_map(|(name, children)| vec![name, children])
+                    .collect(),
+            )
+        }
+        Concrete::Value(_) => content.clone(),
+    };
+    Concrete::List(vec![type_name.clone(), content])
+}
+
+/// Any encoder choices: documents written with them must decode the same.
+fn arb_options() -> impl Strategy<Value = CompressionOptions> {
+    (0usize..8, vec(any::<bool>(), 6)).prop_map(|(min_savings, flags)| CompressionOptions {
+        min_savings,
+        subtree_templates: flags[0],
+        byte_patterns: flags[1],
+        tree_templates: flags[2],
+        patches: flags[3],
+        sequences: flags[4],
+        columns: flags[5],
+    })
+}
+
+proptest! {
+    #[test]
+    fn leaf_tree_round_trip(c in arb_leaf_tree()) {
+        check_all_encodings(&c);
+    }
+
+    #[test]
+    fn dictionary_round_trip(samples in vec(arb_leaf_tree(), 0..4), c in arb_leaf_tree(), options in arb_options()) {
+        let dictionary = TemplateDictionary::load(&TemplateDictionary::train(&samples)).unwrap();
+        let e = PrefixDictionaryEncoding {
+            dictionary: Rc::new(dictionary),
+            options,
+        };
+        check_round_trip(e, &c, &c);
+    }
+
+    #[test]
+    fn windowed_round_trip(c in arb_leaf_tree(), max_templates in 1u32..8, options in arb_options()) {
+        // Small windows, so the encoder and decoder have to evict the same templates
+        let e = PrefixWindowedEncoding {
+            max_templates: NonZeroU32::new(max_templates).unwrap(),
+            options,
+        };
+        check_round_trip(e, &c, &c);
+    }
+
+    #[test]
+    fn tuned_round_trip(c in arb_leaf_tree(), options in arb_options()) {
+        check_round_trip(PrefixTunedEncoding { options }, &c, &c);
+    }
+
+    #[test]
+    fn container_round_trip(c in arb_leaf_tree(), checksum in any::<bool>()) {
+        let flags = if checksum { FLAG_CHECKSUM } else { 0 };
+        for data in [
+            serialize_document(&BasicEncoding, flags, &c),
+            serialize_document(&PrefixCompressedEncoding, flags, &c),
+            serialize_document(&PrefixSizedEncoding, flags, &c),
+        ] {
+            assert_eq!(view_to_concrete(&open(&data, &[]).unwrap()), c);
+        }
+    }
+
+    #[test]
+    fn incremental_round_trip(c in arb_leaf_tree()) {
+        check_incremental(BasicEncoding, &c);
+        check_incremental(PrefixEncoding, &c);
+    }
+
+    #[test]
+    fn type_viewer_round_trip(t in arb_typed_tree()) {
+        check_all_encodings(&TypeViewer(&t));
+    }
+
+    #[test]
+    fn type_viewer_stable(t in arb_typed_tree()) {
+        let leaf = view_to_concrete(&TypeViewer(&t));
+        check_typed_value_layout(&leaf, &t);
+
+        // Viewing the same value again must produce identical output.
+        assert_eq!(
+            PrefixEncoding.serialize(&TypeViewer(&t)),
+            PrefixEncoding.serialize(&TypeViewer(&t))
+        );
+
+        // A copy of the typed tree has independent hash maps, which may visit their entries in another order,
+        // but must otherwise produce the same output.
+        let copy = typed::view_to_concrete(&t);
+        assert_eq!(
+            sort_entries(&leaf),
+            sort_entries(&view_to_concrete(&TypeViewer(&copy)))
+        );
+    }
+}
+
+#[test]
+fn type_viewer_terminal_format() {
+    let t = typed::Concrete {
+        type_name: 3u128,
+        content: typed::StructOrValue::Value(vec![7]),
+    };
+
+    // TypedValue list (2 children)
+    let mut expected = vec![130];
+    // TypeName: 16 byte value
+    expected.extend_from_slice(&[80, 3]);
+    expected.extend_from_slice(&[0; 15]);
+    // Content: 1 byte value
+    expected.extend_from_slice(&[1, 7]);
+
+    assert_eq!(PrefixEncoding.serialize(&TypeViewer(&t)), expected);
+}
@@ -1,75 +1,52 @@
 //! Output tree looks like:
-//! 
+//!
 //! <pre>
 //! TypedValue (List)
-//!     TypeName (List)
-//!         first byte (Value)
-//!         ...
-//!         16th byte (Value)
-//!     Content (List)
-//!         ...
+//!     TypeName (Value): 16 bytes, little endian
+//!     Content
 //! </pre>
-//! 
-//! If Content is Value:
+//!
+//! If Content is Value, it is a single leaf holding all the bytes:
 //! <pre>
-//! Content (List)
-//!     first byte (Value)
-//!     ...
-//!     last byte (Value)
+//! Content (Value)
 //! </pre>
-//! 
+//!
 //! Content can have 0+ bytes
 //!
 //! If Content is Struct/Map:
 //! <pre>
 //! Content (List)
-//!     Map Marker (List) (needed so we can tell empty map from empty Value)
-//!         first map entry (List)
-//!             ChildName (List)
-//!                 first byte (Value)
-//!                 ...
-//!                 16th byte (Value)
-//!             Children List (List)
-//!                 TypedValue (List) (structure recurses here)
-//!         ...
-//!         last map entry (List)
-//!             ...
+//!     first map entry's ChildName (Value): 16 bytes, little endian
+//!     first map entry's Children List (List)
+//!         TypedValue (List) (structure recurses here)
+//!     ...
+//!     last map entry's ChildName (Value)
+//!     last map entry's Children List (List)
 //! </pre>
-//! 
-//! Content can have 0+ map entries
-//! 
+//!
+//! Content can have 0+ map entries, each of which is a ChildName followed by a Children List
+//!
 
 use super::data_models::leaf_tree::{View, Visitor};
 use super::data_models::typed_value_tree::{
     ListView, ListVisitor, MapView, MapVisitor, TypeView, TypeVisitor,
 };
-use byteorder::WriteBytesExt;
+use super::encoding::Encoder;
+use super::into_typed_value_tree::{FieldSchema, Schema};
+use super::leaf_tree_template::{BytePatternChild, BytePatternTemplate, TreeTemplate};
+use super::prefix_encoding::PrefixEncoding;
+use std::io::{self, Write};
 
-struct ByteLister {
-    v: Vec<u8>,
-}
+struct BytesValue(Vec<u8>);
 
-struct ByteValue(u8);
-
-fn make_byte_lister(n: u128) -> ByteLister {
-    let mut wtr = vec![];
-    wtr.write_u128::<byteorder::LittleEndian>(n).unwrap();
-    return ByteLister { v: wtr };
-}
-
-impl View for ByteLister {
-    type Value = u8;
-    fn visit<V: Visitor<Value = u8>>(&self, v: &mut V) {
-        for u in &self.v {
-            v.visit_list(&ByteValue(*u));
-        }
-    }
+fn make_id_value(n: u128) -> BytesValue {
+    BytesValue(n.to_le_bytes().to_vec())
 }
 
-impl View for ByteValue {
-    type Value = u8;
-    fn visit<V: Visitor<Value = u8>>(&self, v: &mut V) {
-        v.visit_value(self.0);
+impl View for BytesValue {
+    type Value = Vec<u8>;
+    fn visit<V: Visitor<Value = Vec<u8>>>(&self, v: &mut V) {
+        v.visit_value(self.0.clone());
     }
 }
 
@@ -78,14 +55,14 @@ impl<T> View for TypeViewer<&T>
 where
     T: TypeView<N = u128>,
 {
-    type Value = u8;
-    fn visit<V: Visitor<Value = u8>>(&self, v: &mut V) {
+    type Value = Vec<u8>;
+    fn visit<V: Visitor<Value = Vec<u8>>>(&self, v: &mut V) {
         let type_name = self.0.apply(TypeGetter(0u128));
 
-        // Type: list of bytes containing type's id
-        v.visit_list(&make_byte_lister(type_name.0));
+        // Type: type's id
+        v.visit_list(&make_id_value(type_name.0));
 
-        // Content: List of map entries OR List of bytes if terminal type
+        // Content: List of map entries OR bytes if terminal type
         v.visit_list(&ContentLister(self.0));
 
         struct TypeGetter(u128);
@@ -107,28 +84,24 @@ impl<T> View for ContentLister<&T>
 where
     T: TypeView<N = u128>,
 {
-    type Value = u8;
-    fn visit<V: Visitor<Value = u8>>(&self, v: &mut V) {
+    type Value = Vec<u8>;
+    fn visit<V: Visitor<Value = Vec<u8>>>(&self, v: &mut V) {
         self.0.visit(&mut ContentListerVisiter(v));
 
         struct ContentListerVisiter<V>(V);
         impl<V> TypeVisitor for ContentListerVisiter<&mut V>
         where
-            V: Visitor<Value = u8>,
+            V: Visitor<Value = Vec<u8>>,
         {
             type N = u128;
 
             fn visit_map<T: MapView<N = Self::N>>(&mut self, _type_name: &Self::N, t: &T) {
-                // Map Marker
-                self.0.visit_list(&MapLister(t));
+                MapLister(t).visit(self.0);
             }
 
             fn visit_value(&mut self, _type_name: &Self::N, t: &[u8]) {
-                // Content:
-                // List of bytes for terminal type
-                for u in t {
-                    self.0.visit_list(&ByteValue(*u));
-                }
+                // Content: bytes for terminal type
+                self.0.visit_value(t.to_vec());
             }
         }
     }
@@ -139,20 +112,20 @@ impl<T> View for MapLister<&T>
 where
     T: MapView<N = u128>,
 {
-    type Value = u8;
-    fn visit<V: Visitor<Value = u8>>(&self, v: &mut V) {
+    type Value = Vec<u8>;
+    fn visit<V: Visitor<Value = Vec<u8>>>(&self, v: &mut V) {
         self.0.visit(&mut MapListerVisiter(v));
 
         struct MapListerVisiter<V>(V);
         impl<V> MapVisitor for MapListerVisiter<&mut V>
         where
-            V: Visitor<Value = u8>,
+            V: Visitor<Value = Vec<u8>>,
         {
             type N = u128;
 
             fn visit<T: ListView<N = Self::N>>(&mut self, name: &Self::N, children: &T) {
-                // Child Name / Map Key / Field Name: list of bytes containing name id
-                self.0.visit_list(&make_byte_lister(*name));
+                // Child Name / Map Key / Field Name: name id
+                self.0.visit_list(&make_id_value(*name));
 
                 // Children List
                 self.0.visit_list(&ChildLister(children));
@@ -166,14 +139,14 @@ impl<T> View for ChildLister<&T>
 where
     T: ListView<N = u128>,
 {
-    type Value = u8;
-    fn visit<V: Visitor<Value = u8>>(&self, v: &mut V) {
+    type Value = Vec<u8>;
+    fn visit<V: Visitor<Value = Vec<u8>>>(&self, v: &mut V) {
         self.0.visit(&mut ChildListerVisiter(v));
 
         struct ChildListerVisiter<V>(V);
         impl<V> ListVisitor for ChildListerVisiter<&mut V>
         where
-            V: Visitor<Value = u8>,
+            V: Visitor<Value = Vec<u8>>,
         {
             type N = u128;
 
@@ -184,3 +157,229 @@ where
         }
     }
 }
+
+/// The tree template matching the TypeViewer of every value with the given schema:
+/// type ids and field names are constants, Terminal bytes are values from the stream and list fields are trees from the stream.
+pub fn schema_tree_template(schema: &Schema) -> TreeTemplate {
+    match schema {
+        Schema::Terminal { id, .. } => TreeTemplate::List(vec![
+            TreeTemplate::ConstantValue(make_id_value(*id).0),
+            TreeTemplate::ValueFromStream,
+        ]),
+        Schema::Struct { id, fields } => {
+            let mut content = vec![];
+            for field in fields {
+                match field {
+                    FieldSchema::Single { name, schema } => {
+                        content.push(TreeTemplate::ConstantValue(make_id_value(*name).0));
+                        content.push(TreeTemplate::List(vec![schema_tree_template(schema)]));
+                    }
+                    FieldSchema::List { name } => {
+                        content.push(TreeTemplate::ConstantValue(make_id_value(*name).0));
+                        content.push(TreeTemplate::TreeFromStream);
+                    }
+                }
+            }
+            TreeTemplate::List(vec![
+                TreeTemplate::ConstantValue(make_id_value(*id).0),
+                TreeTemplate::List(content),
+            ])
+        }
+    }
+}
+
+/// The byte pattern template matching the TypeViewer of every value with the given schema,
+/// if they all have the same number of bytes (every Terminal has a fixed size, and there are no list fields).
+/// Terminal bytes are laid out in the stream in the order they are visited.
+pub fn schema_byte_pattern(schema: &Schema) -> Option<BytePatternTemplate> {
+    let mut size = 0;
+    let content = schema_byte_pattern_child(schema, &mut size)?;
+    Some(BytePatternTemplate { size, content })
+}
+
+fn schema_byte_pattern_child(schema: &Schema, size: &mut u32) -> Option<BytePatternChild> {
+    Some(match schema {
+        Schema::Terminal { id, size: length } => {
+            let length = (*length)?;
+            let offset = *size;
+            *size += length;
+            BytePatternChild::List(vec![
+                BytePatternChild::ConstantValue(make_id_value(*id).0),
+                BytePatternChild::ValueFromStreamAtOffset { offset, length },
+            ])
+        }
+        Schema::Struct { id, fields } => {
+            let mut content = vec![];
+            for field in fields {
+                match field {
+                    FieldSchema::Single { name, schema } => {
+                        content.push(BytePatternChild::ConstantValue(make_id_value(*name).0));
+                        content.push(BytePatternChild::List(vec![schema_byte_pattern_child(
+                            schema, size,
+                        )?]));
+                    }
+                    FieldSchema::List { .. } => return None,
+                }
+            }
+            BytePatternChild::List(vec![
+                BytePatternChild::ConstantValue(make_id_value(*id).0),
+                BytePatternChild::List(content),
+            ])
+        }
+    })
+}
+
+/// Writes the data stream for a use of schema_byte_pattern(schema) reproducing TypeViewer(value): its Terminal bytes, in order.
+///
+/// value must be described by schema (see HasSchema): this is only checked as far as panicking where it does not fit.
+pub fn write_schema_byte_pattern_data<T: TypeView<N = u128>, W: Write>(
+    schema: &Schema,
+    value: &T,
+    out: &mut W,
+) -> io::Result<()> {
+    write_stream(schema, value, StreamFormat::BytePattern, out)
+}
+
+/// Writes the tree stream for a use of schema_tree_template(schema) reproducing TypeViewer(value):
+/// each Terminal's bytes as a value, and each list field's Children List as a tree, in PrefixEncoding.
+///
+/// value must be described by schema (see HasSchema): this is only checked as far as panicking where it does not fit.
+pub fn write_schema_tree_stream<T: TypeView<N = u128>, W: Write>(
+    schema: &Schema,
+    value: &T,
+    out: &mut W,
+) -> io::Result<()> {
+    write_stream(schema, value, StreamFormat::Tree, out)
+}
+
+fn write_stream<T: TypeView<N = u128>, W: Write>(
+    schema: &Schema,
+    value: &T,
+    format: StreamFormat,
+    out: &mut W,
+) -> io::Result<()> {
+    value
+        .apply(StreamWriter {
+            schema,
+            format,
+            out,
+            result: Ok(()),
+        })
+        .result
+}
+
+#[derive(Clone, Copy)]
+enum StreamFormat {
+    BytePattern,
+    Tree,
+}
+
+/// Writes the stream of the value it visits, walking schema alongside it.
+struct StreamWriter<'a, W> {
+    /// The schema of the value being visited.
+    schema: &'a Schema,
+    format: StreamFormat,
+    out: &'a mut W,
+    /// The first error encountered. Once set, nothing more is written.
+    result: io::Result<()>,
+}
+
+impl<'a, W: Write> StreamWriter<'a, W> {
+    fn write(&mut self, write: impl FnOnce(&mut W) -> io::Result<()>) {
+        if self.result.is_ok() {
+            self.result = write(self.out);
+        }
+    }
+}
+
+impl<'a, W: Write> TypeVisitor for StreamWriter<'a, W> {
+    type N = u128;
+
+    fn visit_map<T: MapView<N = Self::N>>(&mut self, type_name: &Self::N, t: &T) {
+        let fields = match self.schema {
+            Schema::Struct { id, fields } if id == type_name => fields,
+            _ => panic!("Struct {} does not match its schema", type_name),
+        };
+        let mut writer = FieldsWriter {
+            fields: fields.iter(),
+            writer: self,
+        };
+        t.visit(&mut writer);
+        assert!(
+            writer.fields.next().is_none(),
+            "Struct {} has fewer fields than its schema",
+            type_name
+        );
+    }
+
+    fn visit_value(&mut self, type_name: &Self::N, t: &[u8]) {
+        match self.schema {
+            Schema::Terminal { id, size } if id == type_name => {
+                if let Some(size) = size {
+                    assert_eq!(
+                        t.len(),
+                        *size as usize,
+                        "Terminal {} has the wrong size",
+                        id
+                    );
+                }
+            }
+            _ => panic!("Terminal {} does not match its schema", type_name),
+        }
+        match self.format {
+            StreamFormat::BytePattern => self.write(|out| out.write_all(t)),
+            StreamFormat::Tree => {
+                self.write(|out| PrefixEncoding.write(&BytesValue(t.to_vec()), out))
+            }
+        }
+    }
+}
+
+struct FieldsWriter<'a, 'b, W> {
+    fields: std::slice::Iter<'a, FieldSchema>,
+    writer: &'b mut StreamWriter<'a, W>,
+}
+
+impl<'a, 'b, W: Write> MapVisitor for FieldsWriter<'a, 'b, W> {
+    type N = u128;
+
+    fn visit<T: ListView<N = Self::N>>(&mut self, name: &Self::N, children: &T) {
+        match self.fields.next() {
+            Some(FieldSchema::Single {
+                name: field,
+                schema,
+            }) if field == name => {
+                let parent = std::mem::replace(&mut self.writer.schema, schema);
+                let count = children
+                    .apply(SingleWriter {
+                        count: 0,
+                        writer: self.writer,
+                    })
+                    .count;
+                assert_eq!(count, 1, "Field {} must have exactly one value", name);
+                self.writer.schema = parent;
+            }
+            Some(FieldSchema::List { name: field }) if field == name => match self.writer.format {
+                StreamFormat::Tree => self
+                    .writer
+                    .write(|out| PrefixEncoding.write(&ChildLister(children), out)),
+                StreamFormat::BytePattern => panic!("Byte pattern schemas have no list fields"),
+            },
+            _ => panic!("Field {} does not match its schema", name),
+        }
+    }
+}
+
+struct SingleWriter<'a, 'b, W> {
+    count: usize,
+    writer: &'b mut StreamWriter<'a, W>,
+}
+
+impl<'a, 'b, W: Write> ListVisitor for SingleWriter<'a, 'b, W> {
+    type N = u128;
+
+    fn visit<T: TypeView<N = Self::N>>(&mut self, child: &T) {
+        self.count += 1;
+        child.visit(self.writer);
+    }
+}
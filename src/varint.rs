@@ -0,0 +1,122 @@
+//! LEB128 style variable length unsigned integers:
+//! 7 bits per byte, least significant group first, with the high bit set on every byte except the last.
+//!
+//! Encodings are required to be minimal (no trailing zero groups), so each integer has exactly one encoding.
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+
+/// The most bytes a u64 can take.
+pub const MAX_VARINT_SIZE: usize = 10;
+
+pub fn varint_size(mut value: u64) -> usize {
+    let mut size = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+    size
+}
+
+pub fn write_varint<W: Write>(out: &mut W, mut value: u64) -> io::Result<()> {
+    while value >= 0x80 {
+        out.write_u8((value as u8) | 0x80)?;
+        value >>= 7;
+    }
+    out.write_u8(value as u8)
+}
+
+/// Rejects encodings which are not minimal, or do not fit in a u64, with io::ErrorKind::InvalidData.
+pub fn read_varint<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    for i in 0..MAX_VARINT_SIZE {
+        let byte = input.read_u8()?;
+        let group = (byte & 0x7f) as u64;
+        if i == MAX_VARINT_SIZE - 1 && group > 1 {
+            return Err(invalid("Varint overflows u64"));
+        }
+        value |= group << (7 * i);
+        if byte & 0x80 == 0 {
+            if byte == 0 && i > 0 {
+                return Err(invalid("Varint is not minimal"));
+            }
+            return Ok(value);
+        }
+    }
+    Err(invalid("Varint overflows u64"))
+}
+
+/// Reads a varint which must fit in a u32, such as a template index.
+pub fn read_varint_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let value = read_varint(input)?;
+    if value > u32::MAX as u64 {
+        return Err(invalid("Varint overflows u32"));
+    }
+    Ok(value as u32)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: u64) -> Vec<u8> {
+        let mut out = vec![];
+        write_varint(&mut out, value).unwrap();
+        assert_eq!(out.len(), varint_size(value));
+        out
+    }
+
+    fn decode(data: &[u8]) -> io::Result<u64> {
+        let mut rdr = data;
+        let value = read_varint(&mut rdr)?;
+        assert!(rdr.is_empty(), "Unexpected data after varint");
+        Ok(value)
+    }
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(encode(0), vec![0]);
+        assert_eq!(encode(127), vec![127]);
+        assert_eq!(encode(128), vec![128, 1]);
+        assert_eq!(encode(300), vec![172, 2]);
+        assert_eq!(encode(u64::MAX).len(), MAX_VARINT_SIZE);
+        for value in [
+            0,
+            1,
+            127,
+            128,
+            16383,
+            16384,
+            u32::MAX as u64,
+            u64::MAX - 1,
+            u64::MAX,
+        ] {
+            assert_eq!(decode(&encode(value)).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        let kind = |data: &[u8]| decode(data).unwrap_err().kind();
+        // Not minimal
+        assert_eq!(kind(&[128, 0]), io::ErrorKind::InvalidData);
+        assert_eq!(kind(&[255, 128, 0]), io::ErrorKind::InvalidData);
+        // Overflow
+        let mut data = vec![255; 9];
+        data.push(2);
+        assert_eq!(kind(&data), io::ErrorKind::InvalidData);
+        assert_eq!(kind(&[255; 11]), io::ErrorKind::InvalidData);
+        assert_eq!(
+            read_varint_u32(&mut &encode(1 << 32)[..])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+        // Truncated
+        assert_eq!(kind(&[128]), io::ErrorKind::UnexpectedEof);
+    }
+}